@@ -3,17 +3,22 @@ use structopt::StructOpt;
 
 use std::path::PathBuf;
 
+use crate::inject::parse_inject_arg;
+use crate::sandbox::Backend;
+use crate::tests::parse_env_arg;
+
 #[derive(Debug, StructOpt)]
 pub(crate) struct Opt {
     /// Specify between 0 and 3 times to control debug information verbosity
     #[structopt(short, long = "verbose", parse(from_occurrences))]
     pub verbosity: usize,
 
-    /// Run every folder in a directory as a test (can be given multiple times)
+    /// Run every folder with a Roottest.toml in a directory tree as a test, named by its path in
+    /// the directory (can be given multiple times)
     #[structopt(short, long)]
     pub directory: Vec<PathBuf>,
 
-    /// Save actual output and do not delete the temporary root/ directory after running the test in it
+    /// Save actual output and do not delete the root directory after running the test in it
     #[structopt(short="C", long="no-cleanup", parse(from_flag = std::ops::Not::not))]
     pub cleanup: bool,
 
@@ -27,6 +32,52 @@ pub(crate) struct Opt {
     /// Include tests with ignore = true
     #[structopt(short, long)]
     pub include_ignored: bool,
+
+    /// How to isolate tests that don't set a backend in Roottest.toml: fakechroot, chroot, unshare, bwrap or native
+    #[structopt(short, long, default_value = "fakechroot")]
+    pub backend: Backend,
+
+    /// Mount root_before copy-on-write under each test's root instead of copying it, for tests that
+    /// don't set overlay in Roottest.toml
+    #[structopt(long)]
+    pub overlay: bool,
+
+    /// Where to put each test's root and actual output, in a subdirectory named after the test.
+    /// Defaults to a new temporary directory
+    #[structopt(short, long)]
+    pub work_dir: Option<PathBuf>,
+
+    /// Copy a host file into each test's root, like `/program=target/debug/program` (can be given
+    /// multiple times)
+    #[structopt(long, parse(try_from_str = parse_inject_arg), number_of_values = 1)]
+    pub inject: Vec<(PathBuf, PathBuf)>,
+
+    /// Also copy the shared libraries that files given with --inject need
+    #[structopt(long)]
+    pub inject_libraries: bool,
+
+    /// Install a host program, by name or path, in each test's root with the libraries it needs
+    /// (can be given multiple times)
+    #[structopt(long, number_of_values = 1)]
+    pub provision: Vec<String>,
+
+    /// Set an environment variable for every test, like `PATH=/bin:/usr/bin`, unless the test's
+    /// environment.toml sets it (can be given multiple times)
+    #[structopt(long, parse(try_from_str = parse_env_arg), number_of_values = 1)]
+    pub env: Vec<(String, String)>,
+
+    /// Run tests that don't set hermetic in Roottest.toml in a known environment, without the
+    /// host's environment variables, umask, file descriptors and ignored signals
+    #[structopt(long)]
+    pub hermetic: bool,
+
+    /// A shell command to run on the host before any test
+    #[structopt(long)]
+    pub setup: Option<String>,
+
+    /// A shell command to run on the host after all tests
+    #[structopt(long)]
+    pub teardown: Option<String>,
 }
 
 pub(crate) fn get_args() -> anyhow::Result<Opt> {
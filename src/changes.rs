@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::matrix::Case;
+use crate::metadata::{normalize_path, MetadataManifest, MetadataOverride};
+use crate::results::{FileNode, Permissions};
+use crate::roots::Root;
+
+/// The expected root described as changes on top of `root_before`, from `changes.toml` and an
+/// optional `root_delta/` holding added and modified files.
+#[derive(Debug)]
+pub struct RootChanges {
+    delete: Vec<PathBuf>,
+    files: BTreeMap<PathBuf, String>,
+    permissions: MetadataManifest,
+    delta: Option<Root>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChangesFile {
+    #[serde(default)]
+    delete: Vec<PathBuf>,
+    #[serde(default)]
+    files: BTreeMap<PathBuf, String>,
+    #[serde(default)]
+    permissions: BTreeMap<PathBuf, MetadataOverride>,
+}
+
+impl RootChanges {
+    /// Load the changes in `dir` for `case`, if it has a `changes.toml` or a `root_delta`.
+    pub fn load(dir: &Path, case: &Case) -> Result<Option<RootChanges>> {
+        let changes_path = case.select(&dir.join("changes.toml"))?;
+        let delta = Root::find_optional(dir, &case.select_root(dir, "root_delta")?)?;
+        if !changes_path.exists() && delta.is_none() {
+            return Ok(None);
+        }
+
+        let changes: ChangesFile = if changes_path.exists() {
+            toml::from_str(&read_to_string(&changes_path).context("read changes.toml")?)
+                .context("parse changes.toml")?
+        } else {
+            ChangesFile::default()
+        };
+
+        Ok(Some(RootChanges {
+            delete: changes
+                .delete
+                .iter()
+                .map(|path| normalize_path(path))
+                .collect::<Result<_>>()?,
+            files: changes
+                .files
+                .into_iter()
+                .map(|(path, contents)| Ok((normalize_path(&path)?, contents)))
+                .collect::<Result<_>>()?,
+            permissions: MetadataManifest::from_overrides(changes.permissions)?,
+            delta,
+        }))
+    }
+
+    /// Turn `root` (the loaded `root_before`) into the expected root.
+    pub fn apply(&self, root: &mut FileNode) -> Result<()> {
+        if let Some(delta) = &self.delta {
+            // Directories in root_delta/ only carry new contents, not new permissions
+            root.merge(delta.load().context("load root_delta")?, true);
+        }
+
+        for path in &self.delete {
+            root.remove(path)
+                .with_context(|| format!("delete {:?}, which does not exist", path))?;
+        }
+
+        for (path, contents) in &self.files {
+            let node = match root.remove(path) {
+                Some(FileNode::File { permissions, .. }) => FileNode::File {
+                    contents: contents.clone().into_bytes(),
+                    permissions,
+                },
+                _ => FileNode::File {
+                    contents: contents.clone().into_bytes(),
+                    permissions: Permissions::for_new(libc::S_IFREG),
+                },
+            };
+            root.insert(path, node)
+                .with_context(|| format!("write {:?}", path))?;
+        }
+
+        self.permissions
+            .overlay(root, false)
+            .context("apply permission changes")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::metadata::{set_mtime, MetadataManifest};
+use crate::overlay::{is_opaque, whiteout_target, OPAQUE_MARKER};
+use crate::results::SpecialKind;
+use crate::roots::mknod;
+
+/// Copy the tree at `source` into `dest`, over anything that's already there, like `rsync -aH`.
+///
+/// Symbolic links, modes, modification times, hard links and special files are preserved. Files
+/// are reflinked when the filesystem supports it. Ownership that can't be applied for lack of
+/// privileges is recorded in `unapplied`.
+pub fn copy_tree(source: &Path, dest: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+    let mut copier = Copier {
+        source,
+        dest,
+        unapplied,
+        hard_links: BTreeMap::new(),
+        whiteouts: false,
+    };
+    copier.copy(Path::new(""))
+}
+
+/// Copy an overlay layer into `dest` like `copy_tree`, removing what its whiteouts remove and
+/// replacing the contents of its opaque directories.
+pub fn copy_layer(source: &Path, dest: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+    let mut copier = Copier {
+        source,
+        dest,
+        unapplied,
+        hard_links: BTreeMap::new(),
+        whiteouts: true,
+    };
+    copier.copy(Path::new(""))
+}
+
+struct Copier<'a> {
+    source: &'a Path,
+    dest: &'a Path,
+    unapplied: &'a mut MetadataManifest,
+    /// Where the first copy of each file with several links went, by device and inode
+    hard_links: BTreeMap<(u64, u64), PathBuf>,
+    /// Whether the source is an overlay layer
+    whiteouts: bool,
+}
+
+impl Copier<'_> {
+    fn copy(&mut self, path: &Path) -> Result<()> {
+        let source = self.source.join(path);
+        let dest = self.dest.join(path);
+        let metadata = source
+            .symlink_metadata()
+            .with_context(|| format!("read metadata of {:?}", source))?;
+        let file_type = metadata.file_type();
+
+        if self.whiteouts {
+            if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                if name == OPAQUE_MARKER {
+                    return Ok(());
+                }
+                if let Some(target) = whiteout_target(name, &metadata) {
+                    let target = parent.join(target);
+                    return remove_existing(&self.dest.join(&target))
+                        .with_context(|| format!("remove {:?}", target));
+                }
+            }
+            if file_type.is_dir() && is_opaque(&source)? {
+                remove_existing(&dest).with_context(|| format!("replace {:?}", path))?;
+            }
+        }
+
+        if file_type.is_dir() {
+            return self.copy_directory(path, &metadata);
+        }
+
+        remove_existing(&dest).with_context(|| format!("replace {:?}", path))?;
+
+        if metadata.nlink() > 1 {
+            if let Some(first) = self.hard_links.get(&(metadata.dev(), metadata.ino())) {
+                return std::fs::hard_link(self.dest.join(first), &dest)
+                    .with_context(|| format!("link {:?} to {:?}", path, first));
+            }
+            self.hard_links
+                .insert((metadata.dev(), metadata.ino()), path.to_owned());
+        }
+
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&source)
+                .with_context(|| format!("read symbolic link {:?}", path))?;
+            std::os::unix::fs::symlink(&target, &dest)
+                .with_context(|| format!("create symbolic link {:?}", path))?;
+        } else if file_type.is_file() {
+            copy_file(&source, &dest).with_context(|| format!("copy file {:?}", path))?;
+        } else if let Some(kind) = SpecialKind::from_file_type(file_type) {
+            mknod(&dest, kind, metadata.mode() & 0o7777, metadata.rdev())
+                .with_context(|| format!("create {} {:?}", kind.name(), path))?;
+        } else {
+            debug!("Skipping {:?} of unknown type", path);
+            return Ok(());
+        }
+
+        self.copy_metadata(path, &metadata)
+    }
+
+    fn copy_directory(&mut self, path: &Path, metadata: &Metadata) -> Result<()> {
+        let dest = self.dest.join(path);
+        match dest.symlink_metadata() {
+            Ok(existing) if existing.is_dir() => {
+                // Make sure the directory can be filled, its mode is set once it's done
+                std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(0o700))
+                    .with_context(|| format!("change mode of {:?}", path))?;
+            }
+            _ => {
+                remove_existing(&dest).with_context(|| format!("replace {:?}", path))?;
+                std::fs::create_dir_all(&dest)
+                    .with_context(|| format!("create directory {:?}", path))?;
+            }
+        }
+
+        let entries = std::fs::read_dir(self.source.join(path))
+            .with_context(|| format!("read directory {:?}", path))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("read directory {:?}", path))?;
+            self.copy(&path.join(entry.file_name()))?;
+        }
+
+        self.copy_metadata(path, metadata)
+    }
+
+    fn copy_metadata(&mut self, path: &Path, metadata: &Metadata) -> Result<()> {
+        let dest = self.dest.join(path);
+        self.unapplied.lchown_or_record(
+            self.dest,
+            path,
+            Some(metadata.uid()),
+            Some(metadata.gid()),
+        )?;
+        // Symbolic links don't have a mode of their own. Changing ownership may clear the setuid
+        // and setgid bits, so the mode comes after it.
+        if !metadata.file_type().is_symlink() {
+            std::fs::set_permissions(&dest, std::fs::Permissions::from_mode(metadata.mode()))
+                .with_context(|| format!("change mode of {:?}", path))?;
+        }
+        set_mtime(&dest, metadata.mtime()).with_context(|| format!("set mtime of {:?}", path))
+    }
+}
+
+/// Remove whatever is at `path`, if anything, so that something else can be created there.
+pub fn remove_existing(path: &Path) -> std::io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(existing) if existing.is_dir() => {
+            make_removable(path)?;
+            std::fs::remove_dir_all(path)
+        }
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Give the current user access to the directories in the tree at `path`, which the program
+/// under test or overlayfs may have taken away.
+fn make_removable(path: &Path) -> std::io::Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_removable(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a regular file's contents, sharing its blocks with the source when the filesystem can.
+fn copy_file(source: &Path, dest: &Path) -> Result<()> {
+    let mut source = File::open(source).context("open source")?;
+    let mut dest = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(dest)
+        .context("create destination")?;
+
+    // SAFETY: both file descriptors are open for the duration of the call
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(());
+    }
+    trace!(
+        "Can't reflink, copying instead: {}",
+        std::io::Error::last_os_error()
+    );
+    std::io::copy(&mut source, &mut dest).context("copy contents")?;
+    Ok(())
+}
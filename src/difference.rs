@@ -27,6 +27,10 @@ pub enum FileNodeDiff {
         target: Option<(PathBuf, PathBuf)>,
         permissions: Option<PermissionsDiff>,
     },
+    SpecialDiffers {
+        device: Option<(u64, u64)>,
+        permissions: Option<PermissionsDiff>,
+    },
 }
 
 #[derive(Debug)]
@@ -62,6 +66,32 @@ pub fn diff_nonempty(diff: &[diff::Result<String>]) -> bool {
     false
 }
 
+/// A line diff of two texts that differ. If they only differ in line endings, which a line diff
+/// doesn't show, the lines are compared with their endings escaped instead.
+pub fn text_diff(actual: &str, expected: &str) -> Diff {
+    let diff: Diff = diff::lines(actual, expected)
+        .into_iter()
+        .map(to_owned_diff_result)
+        .collect();
+    if diff_nonempty(&diff) {
+        return diff;
+    }
+
+    let escaped_lines = |text: &str| -> Vec<String> {
+        text.split_inclusive('\n')
+            .map(|line| line.escape_debug().to_string())
+            .collect()
+    };
+    diff::slice(&escaped_lines(actual), &escaped_lines(expected))
+        .into_iter()
+        .map(|line| match line {
+            diff::Result::Left(s) => diff::Result::Left(s.clone()),
+            diff::Result::Right(s) => diff::Result::Right(s.clone()),
+            diff::Result::Both(s1, s2) => diff::Result::Both(s1.clone(), s2.clone()),
+        })
+        .collect()
+}
+
 fn hunkify_diff(diff: Diff, extra_lines: usize) -> HunkDiff {
     let mut hunks = vec![];
 
@@ -202,15 +232,9 @@ impl FileNodeDiff {
                         String::from_utf8(actual_contents),
                         String::from_utf8(expected_contents),
                     ) {
-                        (Ok(actual_contents), Ok(expected_contents)) => {
-                            let diff: Diff = diff::lines(&actual_contents, &expected_contents)
-                                .into_iter()
-                                .map(to_owned_diff_result)
-                                .collect();
-
-                            assert!(diff_nonempty(&diff));
-                            Some(FileDiff::Diff(diff))
-                        }
+                        (Ok(actual_contents), Ok(expected_contents)) => Some(FileDiff::Diff(
+                            text_diff(&actual_contents, &expected_contents),
+                        )),
                         (_, _) => Some(FileDiff::Binary),
                     }
                 };
@@ -338,6 +362,41 @@ impl FileNodeDiff {
                     },
                 }
             }
+            (
+                FileNode::Special {
+                    kind: actual_kind,
+                    device: actual_device,
+                    permissions: actual_permissions,
+                },
+                FileNode::Special {
+                    kind: expected_kind,
+                    device: expected_device,
+                    permissions: expected_permissions,
+                },
+            ) if actual_kind == expected_kind => {
+                let device = if actual_device == expected_device {
+                    None
+                } else {
+                    Some((actual_device, expected_device))
+                };
+
+                let permissions = if actual_permissions == expected_permissions {
+                    None
+                } else {
+                    Some(PermissionsDiff::from_permissions(
+                        actual_permissions,
+                        expected_permissions,
+                    ))
+                };
+
+                match (device, permissions) {
+                    (None, None) => FileNodeDiff::Identical,
+                    (device, permissions) => FileNodeDiff::SpecialDiffers {
+                        device,
+                        permissions,
+                    },
+                }
+            }
             (actual, expected) => {
                 FileNodeDiff::DifferentType(actual.node_type(), expected.node_type())
             }
@@ -430,10 +489,32 @@ impl FileNodeDiff {
                     );
                 }
             }
+            FileNodeDiff::SpecialDiffers {
+                device,
+                permissions,
+            } => {
+                if let Some(permissions) = permissions {
+                    println!("{}permissions differ:", spaces);
+                    permissions.print(indentation + 2);
+                }
+
+                if let Some((actual, expected)) = device {
+                    println!(
+                        "{}device number differs: actual {}, expected {}",
+                        spaces,
+                        format_device(actual).red(),
+                        format_device(expected).green()
+                    );
+                }
+            }
         }
     }
 }
 
+fn format_device(device: u64) -> String {
+    format!("{}:{}", libc::major(device), libc::minor(device))
+}
+
 impl PermissionsDiff {
     fn from_permissions(actual: Permissions, expected: Permissions) -> Self {
         let mode = if actual.mode == expected.mode {
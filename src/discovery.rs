@@ -0,0 +1,68 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Directories of a test that hold roots, whose files are never tests, along with their variants
+/// for matrix cases like `root_after.C`
+const ROOT_DIRS: &[&str] = &["root_before", "root_after", "root_delta", "root"];
+
+/// Find the tests in `suite` and the directories under it: every directory with a
+/// `Roottest.toml`. Each test is named by its path relative to `suite`, like
+/// `install/upgrade/keeps_config`, or by its directory's name if it's `suite` itself.
+pub fn find_tests(suite: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut tests = Vec::new();
+    if suite.join("Roottest.toml").is_file() {
+        tests.push((suite.to_owned(), test_name(suite)?));
+    }
+    walk(suite, suite, &mut tests)?;
+    Ok(tests)
+}
+
+fn walk(suite: &Path, dir: &Path, tests: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("recurse into {:?}", dir))?
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("read {:?}", dir))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("get type of {:?}", entry.path()))?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_root = |root: &&str| {
+            name.strip_prefix(root)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        };
+        // Symbolic links aren't followed, so that the walk always ends
+        if !file_type.is_dir() || ROOT_DIRS.iter().any(is_root) {
+            continue;
+        }
+        let path = entry.path();
+        if path.join("Roottest.toml").is_file() {
+            let relative = path.strip_prefix(suite).expect("directory in suite");
+            let name = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            tests.push((path.clone(), name));
+        }
+        walk(suite, &path, tests)?;
+    }
+    Ok(())
+}
+
+/// The name of a test given by its directory, rather than found in a suite.
+pub fn test_name(dir: &Path) -> Result<String> {
+    let dir = match dir.file_name() {
+        Some(_) => dir.to_owned(),
+        // Like `.` or `..`
+        None => dir.canonicalize().context("resolve test's directory")?,
+    };
+    Ok(dir
+        .file_name()
+        .context("get name of test's directory")?
+        .to_string_lossy()
+        .into())
+}
@@ -0,0 +1,454 @@
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+const MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: usize = 0;
+const DT_NEEDED: usize = 1;
+const DT_STRTAB: usize = 5;
+const DT_RPATH: usize = 15;
+const DT_RUNPATH: usize = 29;
+/// Where glibc's dynamic loader looks for libraries after the directories in `/etc/ld.so.conf`
+const DEFAULT_LIBRARY_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+
+/// The dynamic loader that the ELF file at `path` asks for, or `None` if it isn't a dynamically
+/// linked ELF file, like a script or a static binary.
+pub fn interpreter(path: &Path) -> Result<Option<PathBuf>> {
+    let data = std::fs::read(path).with_context(|| format!("read {:?}", path))?;
+    if !data.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let elf = Elf::parse(&data).with_context(|| format!("{:?} is not a valid ELF file", path))?;
+
+    let header = match elf
+        .program_headers()?
+        .into_iter()
+        .find(|header| header.kind == PT_INTERP)
+    {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let interpreter = data
+        .get(header.offset..offset(header.offset, header.file_size)?)
+        .context("interpreter is outside of the file")?;
+    let interpreter = interpreter.split(|&b| b == 0).next().unwrap_or_default();
+    Ok(Some(PathBuf::from(OsStr::from_bytes(interpreter))))
+}
+
+/// What the dynamic loader reads from an ELF file to load its libraries.
+#[derive(Debug)]
+pub struct Dynamic {
+    /// The libraries it needs, by name, or by path if they contain a `/`
+    pub needed: Vec<PathBuf>,
+    /// Where its libraries are looked up first: `DT_RUNPATH`, or `DT_RPATH` if it has none, with
+    /// `$ORIGIN` replaced
+    pub search_path: Vec<PathBuf>,
+    /// The class, byte order and machine of the file, which its libraries have to match
+    kind: [u8; 4],
+}
+
+impl Dynamic {
+    /// Whether the file at `path` is an ELF file that can be loaded as one of the libraries.
+    pub fn can_load(&self, path: &Path) -> bool {
+        let mut header = [0; 0x14];
+        let read = std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut header));
+        read.is_ok() && header.starts_with(MAGIC) && kind(&header) == self.kind
+    }
+}
+
+/// The dynamic section of the ELF file at `path`, or `None` if it isn't a dynamically linked ELF
+/// file. Only its own libraries are listed, not theirs.
+pub fn dynamic(path: &Path) -> Result<Option<Dynamic>> {
+    let data = std::fs::read(path).with_context(|| format!("read {:?}", path))?;
+    if !data.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let elf = Elf::parse(&data).with_context(|| format!("{:?} is not a valid ELF file", path))?;
+    let headers = elf.program_headers()?;
+    let section = match headers.iter().find(|header| header.kind == PT_DYNAMIC) {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+
+    let entry_size = if elf.is_64 { 16 } else { 8 };
+    let mut entries = Vec::new();
+    for i in 0..section.file_size / entry_size {
+        let entry = offset(section.offset, i * entry_size)?;
+        let (tag, value) = if elf.is_64 {
+            (elf.u64(entry, 0)?, elf.u64(entry, 8)?)
+        } else {
+            (elf.u32(entry, 0)? as usize, elf.u32(entry, 4)? as usize)
+        };
+        if tag == DT_NULL {
+            break;
+        }
+        entries.push((tag, value));
+    }
+    let value = |wanted: usize| {
+        entries
+            .iter()
+            .find(|(tag, _)| *tag == wanted)
+            .map(|(_, value)| *value)
+    };
+
+    // The string table is given by its address in memory
+    let strings_address = value(DT_STRTAB).context("no string table")?;
+    let segment = headers
+        .iter()
+        .filter(|header| header.kind == PT_LOAD)
+        .find(|header| {
+            header.address <= strings_address && strings_address - header.address < header.file_size
+        })
+        .context("string table is outside of the file")?;
+    let strings = offset(segment.offset, strings_address - segment.address)?;
+    let string = |string_offset: usize| -> Result<&OsStr> {
+        let bytes = data
+            .get(offset(strings, string_offset)?..)
+            .context("string is outside of the file")?;
+        Ok(OsStr::from_bytes(
+            bytes.split(|&b| b == 0).next().unwrap_or_default(),
+        ))
+    };
+
+    let needed = entries
+        .iter()
+        .filter(|(tag, _)| *tag == DT_NEEDED)
+        .map(|(_, offset)| string(*offset).map(PathBuf::from))
+        .collect::<Result<_>>()?;
+    let search_path = match value(DT_RUNPATH).or_else(|| value(DT_RPATH)) {
+        Some(offset) => {
+            let origin = path
+                .canonicalize()
+                .with_context(|| format!("resolve {:?}", path))?;
+            let origin = origin.parent().unwrap_or(&origin).to_string_lossy();
+            string(offset)?
+                .to_string_lossy()
+                .split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| {
+                    PathBuf::from(
+                        dir.replace("${ORIGIN}", &origin)
+                            .replace("$ORIGIN", &origin),
+                    )
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    Ok(Some(Dynamic {
+        needed,
+        search_path,
+        kind: kind(&data),
+    }))
+}
+
+/// The directories that glibc's dynamic loader looks up libraries in after a file's own search
+/// path: those in the host's `/etc/ld.so.conf`, then the default ones. Other loaders, like musl's,
+/// have their own configuration, but usually look in the default directories too.
+pub fn host_library_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    read_ld_so_conf(Path::new("/etc/ld.so.conf"), &mut dirs);
+    dirs.extend(DEFAULT_LIBRARY_DIRS.iter().map(PathBuf::from));
+    dirs
+}
+
+/// Add the directories listed in the `ld.so.conf` at `path`, and in the files it includes, to
+/// `dirs`. A missing file lists none.
+fn read_ld_so_conf(path: &Path, dirs: &mut Vec<PathBuf>) {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            trace!("Can't read {:?}: {}", path, e);
+            return;
+        }
+    };
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include ") {
+            let pattern = path
+                .parent()
+                .unwrap_or_else(|| Path::new("/"))
+                .join(pattern.trim());
+            for included in expand_pattern(&pattern) {
+                read_ld_so_conf(&included, dirs);
+            }
+        } else if line.starts_with('/') {
+            dirs.push(PathBuf::from(line));
+        }
+    }
+}
+
+/// The files matching `pattern`, whose file name may contain `*` wildcards, in order.
+fn expand_pattern(pattern: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Vec::new(),
+    };
+    if !name.contains('*') {
+        return vec![pattern.to_owned()];
+    }
+    let mut matches: Vec<PathBuf> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| wildcard_match(&name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .collect();
+    matches.sort();
+    matches
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            name.starts_with(prefix)
+                && (prefix.len()..=name.len())
+                    .any(|i| name.is_char_boundary(i) && wildcard_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// `base + offset`, for offsets read from a file that may be malformed.
+fn offset(base: usize, offset: usize) -> Result<usize> {
+    base.checked_add(offset).context("offset out of range")
+}
+
+/// The class, byte order and machine in an ELF header.
+fn kind(header: &[u8]) -> [u8; 4] {
+    [header[4], header[5], header[0x12], header[0x13]]
+}
+
+/// The parts of a program header that tell where a segment is.
+struct ProgramHeader {
+    kind: u32,
+    /// In the file
+    offset: usize,
+    /// In memory
+    address: usize,
+    file_size: usize,
+}
+
+/// The parts of the ELF header needed to find the program headers.
+struct Elf<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    big_endian: bool,
+    program_header_offset: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Elf<'a>> {
+        let mut elf = Elf {
+            data,
+            is_64: match data.get(4) {
+                Some(1) => false,
+                Some(2) => true,
+                _ => anyhow::bail!("unknown ELF class"),
+            },
+            big_endian: match data.get(5) {
+                Some(1) => false,
+                Some(2) => true,
+                _ => anyhow::bail!("unknown ELF byte order"),
+            },
+            program_header_offset: 0,
+            program_header_size: 0,
+            program_header_count: 0,
+        };
+        if elf.is_64 {
+            elf.program_header_offset = elf.u64(0, 0x20)?;
+            elf.program_header_size = elf.u16(0, 0x36)?;
+            elf.program_header_count = elf.u16(0, 0x38)?;
+        } else {
+            elf.program_header_offset = elf.u32(0, 0x1c)? as usize;
+            elf.program_header_size = elf.u16(0, 0x2a)?;
+            elf.program_header_count = elf.u16(0, 0x2c)?;
+        }
+        Ok(elf)
+    }
+
+    fn program_headers(&self) -> Result<Vec<ProgramHeader>> {
+        (0..self.program_header_count)
+            .map(|i| {
+                let header = offset(self.program_header_offset, i * self.program_header_size)?;
+                Ok(if self.is_64 {
+                    ProgramHeader {
+                        kind: self.u32(header, 0)?,
+                        offset: self.u64(header, 0x08)?,
+                        address: self.u64(header, 0x10)?,
+                        file_size: self.u64(header, 0x20)?,
+                    }
+                } else {
+                    ProgramHeader {
+                        kind: self.u32(header, 0)?,
+                        offset: self.u32(header, 0x04)? as usize,
+                        address: self.u32(header, 0x08)? as usize,
+                        file_size: self.u32(header, 0x10)? as usize,
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// The `N` bytes of the field at `field` in the structure at `base`.
+    fn bytes<const N: usize>(&self, base: usize, field: usize) -> Result<[u8; N]> {
+        let start = offset(base, field)?;
+        let bytes = self
+            .data
+            .get(start..offset(start, N)?)
+            .context("header is outside of the file")?;
+        let mut bytes: [u8; N] = bytes.try_into().expect("slice of length N");
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn u16(&self, base: usize, field: usize) -> Result<usize> {
+        Ok(u16::from_le_bytes(self.bytes(base, field)?) as usize)
+    }
+
+    fn u32(&self, base: usize, field: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(base, field)?))
+    }
+
+    fn u64(&self, base: usize, field: usize) -> Result<usize> {
+        let value = u64::from_le_bytes(self.bytes(base, field)?);
+        value.try_into().context("offset doesn't fit in memory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERPRETER: &[u8] = b"/lib64/ld-linux-x86-64.so.2\0";
+    const STRINGS: &[u8] = b"\0libfoo.so.1\0libc.so.6\0$ORIGIN/../lib:/opt/lib\0";
+    const INTERPRETER_OFFSET: u64 = 0x40 + 3 * 0x38;
+    const STRINGS_OFFSET: u64 = INTERPRETER_OFFSET + INTERPRETER.len() as u64;
+    const DYNAMIC_OFFSET: u64 = 0x180;
+    const ADDRESS: u64 = 0x400000;
+
+    /// A 64-bit x86-64 executable with an interpreter that needs `libfoo.so.1` and `libc.so.6`,
+    /// with a runpath relative to its directory.
+    fn executable() -> Vec<u8> {
+        let mut data = vec![0; DYNAMIC_OFFSET as usize + 5 * 16];
+        let mut put = |offset: u64, bytes: &[u8]| {
+            data[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, b"\x7fELF\x02\x01\x01");
+        put(0x12, &0x3eu16.to_le_bytes());
+        put(0x20, &0x40u64.to_le_bytes());
+        put(0x36, &0x38u16.to_le_bytes());
+        put(0x38, &3u16.to_le_bytes());
+        let segments = [
+            (PT_INTERP, INTERPRETER_OFFSET, INTERPRETER.len() as u64),
+            (PT_LOAD, 0, DYNAMIC_OFFSET + 5 * 16),
+            (PT_DYNAMIC, DYNAMIC_OFFSET, 5 * 16),
+        ];
+        for (i, (kind, offset, size)) in segments.iter().enumerate() {
+            let header = 0x40 + i as u64 * 0x38;
+            put(header, &kind.to_le_bytes());
+            put(header + 0x08, &offset.to_le_bytes());
+            put(header + 0x10, &(ADDRESS + offset).to_le_bytes());
+            put(header + 0x20, &size.to_le_bytes());
+        }
+        put(INTERPRETER_OFFSET, INTERPRETER);
+        put(STRINGS_OFFSET, STRINGS);
+        let entries = [
+            (DT_NEEDED, 1),
+            (DT_NEEDED, 13),
+            (DT_STRTAB, ADDRESS + STRINGS_OFFSET),
+            (DT_RUNPATH, 23),
+            (DT_NULL, 0),
+        ];
+        for (i, (tag, value)) in entries.iter().enumerate() {
+            let entry = DYNAMIC_OFFSET + i as u64 * 16;
+            put(entry, &(*tag as u64).to_le_bytes());
+            put(entry + 8, &value.to_le_bytes());
+        }
+        data
+    }
+
+    /// Write `data` to a file named `name`, in a directory of the test named `test`.
+    fn write(test: &str, name: &str, data: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("roottest-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_interpreter_and_needed_libraries() {
+        let path = write("reads", "executable", &executable());
+        let interpreter = interpreter(&path).unwrap();
+        let dynamic = dynamic(&path).unwrap().unwrap();
+        let dir = path.parent().unwrap().canonicalize().unwrap();
+        let same_kind = dynamic.can_load(&path);
+        let mut other_machine = executable();
+        other_machine[0x12] = 0xb7;
+        let other_machine = write("reads", "other_machine", &other_machine);
+        let other_kind = dynamic.can_load(&other_machine);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(
+            interpreter,
+            Some(PathBuf::from("/lib64/ld-linux-x86-64.so.2"))
+        );
+        assert_eq!(
+            dynamic.needed,
+            [PathBuf::from("libfoo.so.1"), PathBuf::from("libc.so.6")]
+        );
+        assert_eq!(
+            dynamic.search_path,
+            [dir.join("../lib"), PathBuf::from("/opt/lib")]
+        );
+        assert!(same_kind);
+        assert!(!other_kind);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let mut past_end = executable();
+        past_end[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut truncated = executable();
+        truncated.truncate(DYNAMIC_OFFSET as usize + 8);
+        let mut wrapping_entries = executable();
+        let dynamic_header = 0x40 + 2 * 0x38;
+        wrapping_entries[dynamic_header + 0x08..dynamic_header + 0x10]
+            .copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+
+        let paths = [
+            write("malformed", "past_end", &past_end),
+            write("malformed", "truncated", &truncated),
+            write("malformed", "wrapping_entries", &wrapping_entries),
+        ];
+        let results: Vec<_> = paths.iter().map(|path| dynamic(path).is_err()).collect();
+        let script = write("malformed", "script", b"#!/bin/sh\n");
+        let script_dynamic = dynamic(&script).unwrap();
+        let script_interpreter = interpreter(&script).unwrap();
+        std::fs::remove_dir_all(script.parent().unwrap()).unwrap();
+
+        assert_eq!(results, [true, true, true]);
+        assert!(script_dynamic.is_none());
+        assert!(script_interpreter.is_none());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("*.conf", "libc.conf"));
+        assert!(wildcard_match("x86_64-*.conf", "x86_64-linux-gnu.conf"));
+        assert!(!wildcard_match("*.conf", "libc.conf.bak"));
+        assert!(wildcard_match("exact", "exact"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// Where distributions install libfaketime, besides multiarch directories like
+/// `/usr/lib/x86_64-linux-gnu/faketime`
+const LIBRARY_DIRS: &[&str] = &[
+    "/usr/local/lib/faketime",
+    "/usr/lib/faketime",
+    "/usr/lib64/faketime",
+];
+const LIBRARY_NAME: &str = "libfaketime.so.1";
+
+/// `fake_time` in `Roottest.toml`, the time the clock starts at for the commands run in the
+/// root:
+///
+/// ```toml
+/// fake_time = "2020-01-01T00:00:00Z"
+/// fake_time = { at = "2020-01-01T00:00:00+01:00", freeze = true }
+/// ```
+///
+/// The clock advances from there, unless it's frozen.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum FakeTimeParams {
+    At(String),
+    Table {
+        at: String,
+        #[serde(default)]
+        freeze: bool,
+    },
+}
+
+impl FakeTimeParams {
+    /// Set up libfaketime in `environment`, with the library at `library`.
+    pub fn apply(&self, library: &Path, environment: &mut BTreeMap<String, String>) -> Result<()> {
+        let (at, freeze) = match self {
+            FakeTimeParams::At(at) => (at, false),
+            FakeTimeParams::Table { at, freeze } => (at, *freeze),
+        };
+        let seconds = parse_timestamp(at).with_context(|| format!("parse fake_time {:?}", at))?;
+
+        let mut preload = library.to_string_lossy().into_owned();
+        if let Some(existing) = environment.get("LD_PRELOAD") {
+            preload = format!("{}:{}", preload, existing);
+        }
+        environment.insert("LD_PRELOAD".to_string(), preload);
+        // Seconds since the epoch, so the time zone in the root doesn't matter
+        environment.insert("FAKETIME_FMT".to_string(), "%s".to_string());
+        let start = if freeze { "" } else { "@" };
+        environment.insert("FAKETIME".to_string(), format!("{}{}", start, seconds));
+        // Timeouts and sleeps measure elapsed time, which shouldn't freeze
+        environment.insert("FAKETIME_DONT_FAKE_MONOTONIC".to_string(), "1".to_string());
+        Ok(())
+    }
+}
+
+/// The host's libfaketime.
+pub fn library() -> Result<PathBuf> {
+    let multiarch_dirs = std::fs::read_dir("/usr/lib")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().contains("-linux-"))
+        .map(|entry| entry.path().join("faketime"));
+    LIBRARY_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .chain(multiarch_dirs)
+        .map(|dir| dir.join(LIBRARY_NAME))
+        .find(|candidate| candidate.is_file())
+        .context("find libfaketime, which fake_time needs")
+}
+
+/// Seconds since the epoch of an RFC 3339 timestamp, like `2020-01-01T00:00:00Z`.
+fn parse_timestamp(text: &str) -> Result<i64> {
+    let invalid = || anyhow::anyhow!("expected a time like 2020-01-01T00:00:00Z");
+    let (date, rest) = text.split_at(text.find(['T', 't', ' ']).ok_or_else(invalid)?);
+    let rest = &rest[1..];
+    let (time, offset) = match rest.find(['Z', 'z', '+', '-']) {
+        Some(i) => rest.split_at(i),
+        None => return Err(invalid()),
+    };
+
+    let numbers = |text: &str, separator| -> Result<Vec<i64>> {
+        text.split(separator)
+            .map(|number| number.parse().map_err(|_| invalid()))
+            .collect()
+    };
+    let (year, month, day) = match numbers(date, '-')?[..] {
+        [year @ 0..=9999, month @ 1..=12, day @ 1..=31] => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    let (hour, minute, second) = match numbers(time, ':')?[..] {
+        [hour @ 0..=23, minute @ 0..=59, second @ 0..=60] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            match numbers(&offset[1..], ':')?[..] {
+                [hours @ 0..=23, minutes @ 0..=59] => sign * (hours * 3600 + minutes * 60),
+                _ => return Err(invalid()),
+            }
+        }
+    };
+
+    Ok(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // From Howard Hinnant's date algorithms, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rfc_3339_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_timestamp("2020-01-01T00:00:00Z").unwrap(), 1577836800);
+        assert_eq!(parse_timestamp("2000-02-29 12:30:15z").unwrap(), 951827415);
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z").unwrap(), -1);
+    }
+
+    #[test]
+    fn applies_offsets() {
+        assert_eq!(
+            parse_timestamp("2020-01-01T02:00:00+02:00").unwrap(),
+            1577836800
+        );
+        assert_eq!(
+            parse_timestamp("2019-12-31T19:30:00-04:30").unwrap(),
+            1577836800
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for text in [
+            "",
+            "2020-01-01",
+            "2020-01-01T00:00:00",
+            "2020-13-01T00:00:00Z",
+            "2020-01-01T24:00:00Z",
+            "2020-01-01T00:00Z",
+            "2020-01-01T00:00:00+2",
+            "99999999999999999-01-01T00:00:00Z",
+        ] {
+            assert!(parse_timestamp(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// The `PATH` of a hermetic test that doesn't set one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+const UMASK: libc::mode_t = 0o022;
+/// One more than the highest signal number on Linux
+const SIGNALS: libc::c_int = 65;
+
+/// The environment a hermetic test starts from, before the suite's and the test's own variables.
+pub fn environment(cd: &Path) -> BTreeMap<String, String> {
+    let mut environment = BTreeMap::new();
+    environment.insert("PATH".to_string(), DEFAULT_PATH.to_string());
+    environment.insert("HOME".to_string(), cd.to_string_lossy().into_owned());
+    environment.insert("LC_ALL".to_string(), "C".to_string());
+    environment.insert("TZ".to_string(), "UTC".to_string());
+    environment
+}
+
+/// Undo what the command would otherwise inherit from Roottest's own process, for `pre_exec`:
+/// set a known umask, close inherited descriptors other than stdin, stdout and stderr when the
+/// command starts, and reset ignored and blocked signals.
+pub fn reset_process() -> io::Result<()> {
+    // SAFETY: umask, close_range, fcntl, signal and sigprocmask are async-signal-safe system
+    // calls, and the signal set is plain data
+    unsafe {
+        libc::umask(UMASK);
+
+        // Marked close-on-exec rather than closed, since the standard library reports exec
+        // failures through one of them
+        let marked = libc::syscall(
+            libc::SYS_close_range,
+            3,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        );
+        if marked == -1 {
+            // Kernels before 5.11 don't have close_range with CLOSE_RANGE_CLOEXEC
+            let mut limit: libc::rlimit = std::mem::zeroed();
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let highest = limit.rlim_cur.min(libc::c_int::MAX as libc::rlim_t) as libc::c_int;
+            for fd in 3..highest {
+                // Fails for descriptors that aren't open, which is fine
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+
+        // Handlers are reset by exec anyway, but ignored signals stay ignored. Signals that
+        // can't be changed, like SIGKILL, make signal fail, which is fine.
+        for signal in 1..SIGNALS {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        if libc::sigprocmask(libc::SIG_SETMASK, &signals, std::ptr::null_mut()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
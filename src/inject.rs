@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::copy::remove_existing;
+use crate::elf;
+use crate::overlay::{whiteout_target, WHITEOUT_PREFIX};
+use crate::results::FileNode;
+use crate::sandbox::host_program;
+
+/// How many symbolic links are followed when resolving a path, like `MAXSYMLINKS` on Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// A host file to copy into the root, by its path in the root:
+///
+/// ```toml
+/// [inject]
+/// "/program" = "../../target/debug/program"
+/// "/usr/bin/helper" = { path = "/usr/bin/helper", libraries = true }
+/// ```
+///
+/// With `libraries`, the shared libraries it needs are copied as well, unless the root has them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum InjectParams {
+    Path(PathBuf),
+    Table {
+        path: PathBuf,
+        #[serde(default)]
+        libraries: bool,
+    },
+}
+
+impl InjectParams {
+    pub fn path(&self) -> &Path {
+        match self {
+            InjectParams::Path(path) | InjectParams::Table { path, .. } => path,
+        }
+    }
+
+    pub fn libraries(&self) -> bool {
+        matches!(
+            self,
+            InjectParams::Table {
+                libraries: true,
+                ..
+            }
+        )
+    }
+}
+
+/// Parse `--inject` arguments, like `/program=target/debug/program`.
+pub fn parse_inject_arg(arg: &str) -> Result<(PathBuf, PathBuf)> {
+    let (path, host_path) = arg
+        .split_once('=')
+        .context("expected PATH_IN_ROOT=HOST_PATH")?;
+    Ok((path.into(), host_path.into()))
+}
+
+/// Files added to a test's root for the run, which aren't part of the root being tested, like
+/// injected files and provisioned programs. They're left out of the roots that are compared, and
+/// removed after the run.
+#[derive(Debug, Default)]
+pub struct Injection {
+    /// The layers of the root, from the top down. Files are added to the top one: the root, or
+    /// the upper layer of a copy-on-write root.
+    layers: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    /// Directories that were created to hold the files, from the top down
+    new_directories: Vec<PathBuf>,
+    /// Directories of lower layers that had to be copied up to the upper layer
+    copied_up_directories: Vec<PathBuf>,
+    /// Directories for Roottest itself, left out and removed with everything in them
+    private_directories: Vec<PathBuf>,
+}
+
+impl Injection {
+    pub fn new(layers: Vec<PathBuf>) -> Injection {
+        Injection {
+            layers,
+            ..Injection::default()
+        }
+    }
+
+    /// Where the files are added.
+    pub fn dest(&self) -> &Path {
+        &self.layers[0]
+    }
+
+    /// Copy `files`, host paths by their path in the root. They can't replace anything.
+    pub fn inject(&mut self, files: &BTreeMap<PathBuf, InjectParams>) -> Result<()> {
+        for (path, params) in files {
+            let host_path = params.path();
+            debug!("Injecting {:?} as {:?}", host_path, path);
+            self.copy_with_libraries(path, host_path, params.libraries(), false)
+                .with_context(|| format!("inject {:?} as {:?}", host_path, path))?;
+        }
+        Ok(())
+    }
+
+    /// Copy `programs`, host programs by name or path, to their path on the host with their
+    /// libraries, unless the root has them.
+    pub fn provision(&mut self, programs: &[String]) -> Result<()> {
+        for program in programs {
+            let host_path = if program.contains('/') {
+                anyhow::ensure!(
+                    Path::new(program).is_absolute(),
+                    "{:?} is neither a name nor an absolute path",
+                    program
+                );
+                PathBuf::from(program)
+            } else {
+                host_program(program)?
+            };
+            debug!("Provisioning {:?}", host_path);
+            self.copy_with_libraries(&host_path, &host_path, true, true)
+                .with_context(|| format!("provision {}", program))?;
+        }
+        Ok(())
+    }
+
+    /// Copy the file at `host_path` to `path` in the root, and its libraries if `libraries`.
+    /// Returns where it went, relative to the root. Files that exist already are kept if
+    /// `keep_existing`, otherwise they're an error.
+    pub fn copy_with_libraries(
+        &mut self,
+        path: &Path,
+        host_path: &Path,
+        libraries: bool,
+        keep_existing: bool,
+    ) -> Result<PathBuf> {
+        let path = match self.prepare(path, keep_existing)? {
+            Some(path) => {
+                std::fs::copy(host_path, self.dest().join(&path))
+                    .with_context(|| format!("copy {:?}", host_path))?;
+                self.files.push(path.clone());
+                path
+            }
+            None => return resolve(&self.layers, path),
+        };
+        if !libraries {
+            return Ok(path);
+        }
+
+        if let Some(interpreter) = elf::interpreter(host_path)? {
+            self.copy_with_libraries(&interpreter, &interpreter, false, true)
+                .with_context(|| format!("copy {:?} for {:?}", interpreter, path))?;
+        }
+        let dynamic = match elf::dynamic(host_path)? {
+            Some(dynamic) => dynamic,
+            None => return Ok(path),
+        };
+        for name in &dynamic.needed {
+            let library = match self
+                .find_library(&dynamic, name)
+                .with_context(|| format!("find {:?} needed by {:?}", name, host_path))?
+            {
+                Some(library) => library,
+                None => continue,
+            };
+            // Then the libraries it needs, unless it was copied already
+            self.copy_with_libraries(&library, &library, true, true)
+                .with_context(|| format!("copy {:?} for {:?}", library, path))?;
+        }
+        Ok(path)
+    }
+
+    /// Where the host has the library `name` that a file with `dynamic` needs, or `None` if the
+    /// root has it. It's looked up in the file's own search path, then in the host's library
+    /// directories, first in the root and then on the host.
+    fn find_library(&self, dynamic: &elf::Dynamic, name: &Path) -> Result<Option<PathBuf>> {
+        let candidates: Vec<PathBuf> = if name.to_string_lossy().contains('/') {
+            vec![name.to_owned()]
+        } else {
+            dynamic
+                .search_path
+                .iter()
+                .cloned()
+                .chain(elf::host_library_dirs())
+                .map(|dir| dir.join(name))
+                .collect()
+        };
+        for candidate in &candidates {
+            let in_root = match resolve(&self.layers, candidate) {
+                Ok(path) => find(&self.layers, &path).is_some() || self.files.contains(&path),
+                Err(_) => false,
+            };
+            if in_root {
+                trace!("{:?} is in the root already", candidate);
+                return Ok(None);
+            }
+        }
+        candidates
+            .into_iter()
+            .find(|candidate| dynamic.can_load(candidate))
+            .map(Some)
+            .context("not found in the root nor on the host")
+    }
+
+    /// Add a hard link at `path` to `target`, a file added before, by where it went.
+    pub fn link(&mut self, path: &Path, target: &Path) -> Result<()> {
+        let path = self.prepare(path, false)?.expect("new file");
+        std::fs::hard_link(self.dest().join(target), self.dest().join(&path))
+            .with_context(|| format!("link {:?} to {:?}", path, target))?;
+        self.files.push(path);
+        Ok(())
+    }
+
+    /// Add a directory at `path` for Roottest's own files, which are all left out of the root.
+    pub fn create_private_directory(&mut self, path: &Path) -> Result<()> {
+        let path = self.prepare(path, false)?.expect("new directory");
+        std::fs::create_dir(self.dest().join(&path))
+            .with_context(|| format!("create {:?}", path))?;
+        self.private_directories.push(path);
+        Ok(())
+    }
+
+    /// Resolve `path` in the root and create its missing parents. Returns `None` if the root
+    /// has it already and `keep_existing` is set.
+    fn prepare(&mut self, path: &Path, keep_existing: bool) -> Result<Option<PathBuf>> {
+        let path = resolve(&self.layers, path)?;
+        if find(&self.layers, &path).is_some() || self.files.contains(&path) {
+            if keep_existing {
+                trace!("{:?} is in the root already", path);
+                return Ok(None);
+            }
+            anyhow::bail!("{:?} is in the root already", path);
+        }
+
+        let parent = path.parent().expect("resolved path has a file name");
+        for directory in parent.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            let dest = self.dest().join(directory);
+            if dest.is_dir() {
+                continue;
+            }
+            let existing = find(&self.layers, directory);
+            std::fs::create_dir(&dest).with_context(|| format!("create {:?}", directory))?;
+            match existing {
+                Some(existing) => {
+                    // Copy the directory up the way overlayfs would
+                    let metadata = existing
+                        .metadata()
+                        .with_context(|| format!("read metadata of {:?}", existing))?;
+                    std::fs::set_permissions(&dest, metadata.permissions())
+                        .with_context(|| format!("change mode of {:?}", directory))?;
+                    if let Err(e) =
+                        std::os::unix::fs::lchown(&dest, Some(metadata.uid()), Some(metadata.gid()))
+                    {
+                        debug!("Can't change owner of {:?}: {}", directory, e);
+                    }
+                    self.copied_up_directories.push(directory.to_owned());
+                }
+                None => self.new_directories.push(directory.to_owned()),
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// Leave the added files out of a root loaded from the layers.
+    pub fn exclude(&self, root: &mut FileNode) {
+        for path in self.files.iter().chain(&self.private_directories) {
+            root.remove(path);
+        }
+        for directory in self.new_directories.iter().rev() {
+            if let Some(FileNode::Directory { children, .. }) = root.find_mut(directory) {
+                if children.is_empty() {
+                    root.remove(directory);
+                }
+            }
+        }
+    }
+
+    /// Remove the added files, and the directories created for them unless the program put
+    /// something else in them.
+    pub fn remove(&self) -> Result<()> {
+        for directory in &self.private_directories {
+            remove_existing(&self.dest().join(directory))
+                .with_context(|| format!("remove {:?}", directory))?;
+        }
+        for file in &self.files {
+            let path = self.dest().join(file);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("remove {:?}", file));
+                }
+                _ => {}
+            }
+        }
+
+        let mut directories: Vec<&PathBuf> = self
+            .new_directories
+            .iter()
+            .chain(&self.copied_up_directories)
+            .collect();
+        // Children before their parents
+        directories.sort_by(|a, b| b.cmp(a));
+        for directory in directories {
+            if let Err(e) = std::fs::remove_dir(self.dest().join(directory)) {
+                debug!("Keeping {:?}: {}", directory, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the directories leading to `path` in the root made of `layers`, from the top down,
+/// following symbolic links the way the program in the root would. Returns the path relative to
+/// the root.
+fn resolve(layers: &[PathBuf], path: &Path) -> Result<PathBuf> {
+    let mut pending: Vec<OsString> = components(path)?;
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let is_last = pending.is_empty();
+        let link = match find(layers, &candidate) {
+            Some(existing) if !is_last => existing
+                .symlink_metadata()
+                .with_context(|| format!("read metadata of {:?}", existing))?
+                .file_type()
+                .is_symlink()
+                .then_some(existing),
+            _ => None,
+        };
+        match link {
+            Some(link) => {
+                links += 1;
+                anyhow::ensure!(
+                    links <= MAX_SYMLINKS,
+                    "too many symbolic links in {:?}",
+                    path
+                );
+                let target = std::fs::read_link(&link)
+                    .with_context(|| format!("read symbolic link {:?}", candidate))?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(components(&target)?);
+            }
+            None => resolved = candidate,
+        }
+    }
+    anyhow::ensure!(
+        resolved.file_name().is_some(),
+        "{:?} is not a file in the root",
+        path
+    );
+    Ok(resolved)
+}
+
+/// The names in `path`, in reverse order, without the root directory and `.`.
+fn components(path: &Path) -> Result<Vec<OsString>> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => components.push(OsString::from("..")),
+            Component::Normal(name) => components.push(name.to_owned()),
+            Component::Prefix(_) => anyhow::bail!("unexpected path prefix in {:?}", path),
+        }
+    }
+    components.reverse();
+    Ok(components)
+}
+
+/// The topmost layer's copy of `path`, unless the root doesn't have it.
+fn find(layers: &[PathBuf], path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    for layer in layers {
+        let candidate = layer.join(path);
+        if let Ok(metadata) = candidate.symlink_metadata() {
+            return match whiteout_target(name, &metadata) {
+                Some(_) => None,
+                None => Some(candidate),
+            };
+        }
+        let mut marker = OsString::from(WHITEOUT_PREFIX);
+        marker.push(name);
+        if candidate.with_file_name(marker).symlink_metadata().is_ok() {
+            return None;
+        }
+    }
+    None
+}
@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+use serde::Deserialize;
+
+use crate::streams::{Streams, Wait};
+
+/// How long `expect` waits by default, in seconds
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// One action of an `interaction` in `Roottest.toml`, a script that types into the program
+/// instead of `input.stdin`:
+///
+/// ```toml
+/// interaction = [
+///     { expect = "Continue\\? \\[y/N\\]" },
+///     { send = "y\n" },
+///     { expect = "Installed", timeout = 60 },
+///     { close_stdin = true },
+/// ]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ActionParams {
+    /// A regular expression to wait for in stdout or stderr
+    expect: Option<String>,
+    /// How long to wait for `expect`, in seconds
+    timeout: Option<u64>,
+    send: Option<String>,
+    #[serde(default)]
+    close_stdin: bool,
+}
+
+#[derive(Debug)]
+pub struct Interaction {
+    actions: Vec<Action>,
+}
+
+#[derive(Debug)]
+enum Action {
+    Expect { regex: Regex, timeout: Duration },
+    Send(String),
+    CloseStdin,
+}
+
+impl Interaction {
+    pub fn new(params: &[ActionParams]) -> Result<Interaction> {
+        let actions = params
+            .iter()
+            .enumerate()
+            .map(|(i, params)| {
+                Action::new(params).with_context(|| format!("load interaction action {}", i + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Interaction { actions })
+    }
+
+    /// Run the script against the program's streams. Returns the transcript of the actions that
+    /// were done, up to the one that failed if one did.
+    pub fn run(&self, streams: &mut Streams) -> Result<(Vec<u8>, bool)> {
+        let mut transcript = String::new();
+        for action in &self.actions {
+            transcript.push_str(&action.to_string());
+            match action {
+                Action::Expect { regex, timeout } => {
+                    debug!("Waiting for /{}/", regex);
+                    let deadline = Instant::now() + *timeout;
+                    let wait = streams
+                        .wait_for(Some(deadline), |unread| {
+                            regex.find(unread).map(|found| found.end())
+                        })
+                        .context("wait for program's output")?;
+                    let failure = match wait {
+                        Wait::Found => None,
+                        Wait::TimedOut => Some(format!("timed out after {}s", timeout.as_secs())),
+                        Wait::Ended => Some("output ended".to_string()),
+                    };
+                    if let Some(failure) = failure {
+                        debug!("Interaction failed: {}", failure);
+                        transcript.push_str(&format!(": {}\n", failure));
+                        transcript.push_str(&format!(
+                            "unread output {:?}\n",
+                            String::from_utf8_lossy(streams.unread())
+                        ));
+                        return Ok((transcript.into_bytes(), false));
+                    }
+                }
+                Action::Send(text) => streams.send(text.as_bytes()),
+                Action::CloseStdin => streams.close_input(),
+            }
+            transcript.push('\n');
+        }
+        Ok((transcript.into_bytes(), true))
+    }
+
+    /// The transcript of the whole script, when every action succeeds.
+    pub fn expected_transcript(&self) -> Vec<u8> {
+        self.actions
+            .iter()
+            .map(|action| format!("{}\n", action))
+            .collect::<String>()
+            .into_bytes()
+    }
+}
+
+impl Action {
+    fn new(params: &ActionParams) -> Result<Action> {
+        let action = match params {
+            ActionParams {
+                expect: Some(expect),
+                timeout,
+                send: None,
+                close_stdin: false,
+            } => Action::Expect {
+                regex: Regex::new(expect).context("parse expect")?,
+                timeout: Duration::from_secs(timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            },
+            ActionParams {
+                expect: None,
+                timeout: None,
+                send: Some(send),
+                close_stdin: false,
+            } => Action::Send(send.clone()),
+            ActionParams {
+                expect: None,
+                timeout: None,
+                send: None,
+                close_stdin: true,
+            } => Action::CloseStdin,
+            _ => anyhow::bail!(
+                "expected one of expect (with an optional timeout), send or close_stdin"
+            ),
+        };
+        Ok(action)
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Expect { regex, .. } => write!(f, "expect /{}/", regex),
+            Action::Send(text) => write!(f, "send {:?}", text),
+            Action::CloseStdin => write!(f, "close stdin"),
+        }
+    }
+}
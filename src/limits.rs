@@ -0,0 +1,217 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ExitStatus};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// How much less CPU time than `cpu_time` a command stopped at the limit may be reported to have
+/// used, since the kernel checks the limit and accounts the time differently
+const CPU_TIME_SLACK: Duration = Duration::from_millis(100);
+
+/// The `[limits]` table of a test, resource limits for the commands run in the root:
+///
+/// ```toml
+/// [limits]
+/// memory = "512M"
+/// cpu_time = 10
+/// file_size = "1M"
+/// open_files = 64
+/// processes = 100
+/// ```
+///
+/// Only `cpu_time` and `file_size` end the command with a signal of their own, so they're the only
+/// limits a failure can be blamed on. Running out of the others makes calls fail, which only the
+/// program sees, and there are no cgroups to account for them.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Bytes of address space
+    #[serde(default, deserialize_with = "size")]
+    memory: Option<u64>,
+    /// Seconds of CPU time
+    cpu_time: Option<u64>,
+    /// Bytes per file. Writes past it fail with EFBIG, like on a full disk.
+    #[serde(default, deserialize_with = "size")]
+    file_size: Option<u64>,
+    open_files: Option<u64>,
+    /// Processes of the user the command runs as, in all of the system
+    processes: Option<u64>,
+}
+
+impl Limits {
+    /// Apply the limits to the current process, for `pre_exec`.
+    pub fn apply(&self) -> io::Result<()> {
+        if let Some(cpu_time) = self.cpu_time {
+            // SIGXCPU at the soft limit, then SIGKILL a second later if it's ignored
+            set_limit(libc::RLIMIT_CPU, cpu_time, cpu_time.saturating_add(1))?;
+        }
+        if let Some(memory) = self.memory {
+            set_limit(libc::RLIMIT_AS, memory, memory)?;
+        }
+        if let Some(file_size) = self.file_size {
+            set_limit(libc::RLIMIT_FSIZE, file_size, file_size)?;
+            // SAFETY: signal is async-signal-safe, and ignoring SIGXFSZ needs no handler
+            if unsafe { libc::signal(libc::SIGXFSZ, libc::SIG_IGN) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(open_files) = self.open_files {
+            set_limit(libc::RLIMIT_NOFILE, open_files, open_files)?;
+        }
+        if let Some(processes) = self.processes {
+            set_limit(libc::RLIMIT_NPROC, processes, processes)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none()
+            && self.cpu_time.is_none()
+            && self.file_size.is_none()
+            && self.open_files.is_none()
+            && self.processes.is_none()
+    }
+
+    /// The limit that killed a command that ended with `status` after using `cpu_time`, if it was
+    /// killed by one.
+    pub fn hit_by(&self, status: ExitStatus, cpu_time: Duration) -> Option<&'static str> {
+        // Anyone can send these signals, so they only count if the limit was used up
+        let cpu_time_used = self
+            .cpu_time
+            .is_some_and(|limit| cpu_time + CPU_TIME_SLACK >= Duration::from_secs(limit));
+        match status.signal()? {
+            libc::SIGXCPU | libc::SIGKILL if cpu_time_used => Some("cpu_time"),
+            libc::SIGXFSZ if self.file_size.is_some() => Some("file_size"),
+            _ => None,
+        }
+    }
+}
+
+/// Wait for `process` to end, and get the CPU time it used.
+pub fn wait(process: &Child) -> io::Result<(ExitStatus, Duration)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain data
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: wait4 only writes to status and usage
+    while unsafe { libc::wait4(process.id() as libc::pid_t, &mut status, 0, &mut usage) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    let duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    Ok((
+        ExitStatus::from_raw(status),
+        duration(usage.ru_utime) + duration(usage.ru_stime),
+    ))
+}
+
+#[cfg(target_env = "gnu")]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type Resource = libc::c_int;
+
+fn set_limit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft as libc::rlim_t,
+        rlim_max: hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit is async-signal-safe and only reads the limit
+    if unsafe { libc::setrlimit(resource, &limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A size in bytes, or with a `K`, `M` or `G` suffix.
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_size(&text)
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("{:#}", e))),
+    }
+}
+
+fn parse_size(text: &str) -> Result<u64> {
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((i, 'K')) => (&text[..i], 1 << 10),
+        Some((i, 'M')) => (&text[..i], 1 << 20),
+        Some((i, 'G')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid size {:?}", text))?;
+    number
+        .checked_mul(multiplier)
+        .with_context(|| format!("size {:?} is too large", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("512 M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for text in ["", "M", "1.5M", "-1", "1T", "18446744073709551615K"] {
+            assert!(parse_size(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn blames_signals_on_used_up_limits() {
+        let limits: Limits = toml::from_str("cpu_time = 1\nfile_size = 10").unwrap();
+        let killed_by = |signal| ExitStatus::from_raw(signal);
+        let used_up = Duration::from_millis(950);
+        assert_eq!(
+            limits.hit_by(killed_by(libc::SIGXCPU), used_up),
+            Some("cpu_time")
+        );
+        assert_eq!(
+            limits.hit_by(killed_by(libc::SIGKILL), used_up),
+            Some("cpu_time")
+        );
+        assert_eq!(
+            limits.hit_by(killed_by(libc::SIGKILL), Duration::ZERO),
+            None
+        );
+        assert_eq!(
+            limits.hit_by(killed_by(libc::SIGXFSZ), Duration::ZERO),
+            Some("file_size")
+        );
+        assert_eq!(
+            Limits::default().hit_by(killed_by(libc::SIGXFSZ), used_up),
+            None
+        );
+        assert_eq!(limits.hit_by(ExitStatus::from_raw(1 << 8), used_up), None);
+    }
+
+    #[test]
+    fn reads_sizes_in_limits() {
+        let limits: Limits = toml::from_str("memory = \"1M\"\nfile_size = 10").unwrap();
+        assert_eq!(limits.memory, Some(1 << 20));
+        assert_eq!(limits.file_size, Some(10));
+        assert!(toml::from_str::<Limits>("memory = \"lots\"").is_err());
+    }
+}
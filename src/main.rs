@@ -2,16 +2,44 @@
 extern crate log;
 
 mod args;
+mod changes;
+mod copy;
 mod difference;
+mod discovery;
+mod elf;
+mod faketime;
+mod hermetic;
+mod inject;
+mod interaction;
+mod limits;
+mod matrix;
+mod metadata;
+mod mock;
+mod overlay;
 mod results;
+mod roots;
+mod sandbox;
+mod schedule;
+mod steps;
+mod streams;
+mod suite;
 mod tests;
+mod tty;
 
 use anyhow::{Context, Result};
 use crossterm::style::Styler;
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{CString, OsString};
 use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 
 fn main() {
+    if let Some(status) = mock::run_as_shim() {
+        std::process::exit(status);
+    }
+
     match run() {
         Ok(true) => std::process::exit(0),
         Ok(false) => std::process::exit(1),
@@ -26,42 +54,142 @@ fn run() -> Result<bool> {
     let opt = args::get_args().context("parse arguments")?;
     trace!("Options: {:#?}", opt);
 
-    let mut test_dirs = opt.tests.clone();
-    for dir in opt.directory {
+    let mut test_dirs = Vec::new();
+    for dir in &opt.tests {
+        test_dirs.push((dir.clone(), discovery::test_name(dir)?, None));
+    }
+    for suite in &opt.directory {
+        let found =
+            discovery::find_tests(suite).with_context(|| format!("find tests in {:?}", suite))?;
         test_dirs.extend(
-            std::fs::read_dir(&dir)
-                .with_context(|| format!("recurse into {:?}", dir))?
-                .flat_map(|entry| entry.map(|entry| entry.path())),
-        )
+            found
+                .into_iter()
+                .map(|(dir, name)| (dir, name, Some(suite.clone()))),
+        );
     }
 
+    let work_dir = match &opt.work_dir {
+        Some(work_dir) => work_dir.clone(),
+        None => create_private_work_dir().context("create work directory")?,
+    };
+    debug!("Work directory: {:?}", work_dir);
+
+    let result = run_tests(&opt, test_dirs, &work_dir);
+    if !opt.cleanup {
+        if opt.quiet <= 1 {
+            println!("Roots and actual output kept in {}", work_dir.display());
+        }
+    } else if opt.work_dir.is_none() {
+        // Also after an error, so that it isn't left behind
+        let cleaned_up = copy::remove_existing(&work_dir).context("clean up work directory");
+        // An error running the tests is the one to report
+        return result.and_then(|passed| cleaned_up.map(|()| passed));
+    }
+    result
+}
+
+fn run_tests(
+    opt: &args::Opt,
+    test_dirs: Vec<(PathBuf, String, Option<PathBuf>)>,
+    work_dir: &Path,
+) -> Result<bool> {
+    let options = tests::RunOptions {
+        cleanup: opt.cleanup,
+        include_ignored: opt.include_ignored,
+        backend: opt.backend,
+        overlay: opt.overlay,
+        inject: opt
+            .inject
+            .iter()
+            .map(|(path, host_path)| {
+                let params = inject::InjectParams::Table {
+                    path: host_path.clone(),
+                    libraries: opt.inject_libraries,
+                };
+                (path.clone(), params)
+            })
+            .collect(),
+        provision: opt.provision.clone(),
+        environment: opt.env.iter().cloned().collect(),
+        hermetic: opt.hermetic,
+    };
+
     info!("Loading {} tests", test_dirs.len());
     trace!("Test directories: {:#?}", test_dirs);
 
     let mut tests = Vec::with_capacity(test_dirs.len());
-    for test_dir in test_dirs {
-        tests.push(
-            tests::RootTest::from_dir(&test_dir)
+    for (test_dir, test_name, suite) in test_dirs {
+        tests.extend(
+            tests::RootTest::from_dir(&test_dir, &test_name, suite.as_deref(), work_dir, &options)
                 .with_context(|| format!("load test from {:?}", test_dir))?,
         );
     }
+    schedule::load_prerequisites(&mut tests, work_dir, &options)
+        .context("load prerequisites of chained tests")?;
+    let tests = schedule::order(tests).context("order chained tests")?;
+    check_work_dir(work_dir, &tests).context("check work directory")?;
     trace!("Tests: {:#?}", tests);
 
     if opt.quiet == 0 {
         println!("Running {} roottests\n", tests.len());
     }
 
+    if let Some(setup) = &opt.setup {
+        run_suite_hook("setup", setup).context("run suite setup")?;
+    }
+
     let mut counts = results::Counts::default();
     let mut fails = Vec::new();
+    // Final roots of tests that chained tests start from, None if the test didn't pass
+    let mut finished_roots = BTreeMap::new();
+    let mut ignored_tests = BTreeSet::new();
+    let mut remaining_dependents = BTreeMap::new();
+    for test in &tests {
+        if let Some(prerequisite) = test.prerequisite() {
+            *remaining_dependents
+                .entry(prerequisite.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+
     for test in tests {
         if opt.quiet == 0 {
             print!("{} ... ", test.name);
             std::io::stdout().flush().unwrap();
         }
 
-        let result = test
-            .run(opt.cleanup, opt.include_ignored)
-            .with_context(|| format!("run test {}", test.name))?;
+        let (result, finished_root) = match test.prerequisite() {
+            None => test.run(&options, None),
+            Some(prerequisite) => match finished_roots.get(prerequisite) {
+                Some(Some(finished_root)) => test.run(&options, Some(finished_root)),
+                _ if ignored_tests.contains(prerequisite) => {
+                    Ok((results::RootTestResult::Ignored, None))
+                }
+                _ => Ok((results::RootTestResult::skipped(prerequisite), None)),
+            },
+        }
+        .with_context(|| format!("run test {}", test.name))?;
+
+        if let Some(prerequisite) = test.prerequisite() {
+            let remaining = remaining_dependents
+                .get_mut(prerequisite)
+                .expect("dependents counted");
+            *remaining -= 1;
+            if *remaining == 0 && opt.cleanup {
+                if let Some(Some(finished_root)) = finished_roots.remove(prerequisite) {
+                    debug!("Cleaning up root of {}", prerequisite);
+                    finished_root
+                        .remove()
+                        .context("clean up temporary root directory")?;
+                }
+            }
+        }
+        if test.keep_root {
+            finished_roots.insert(test.name.clone(), finished_root);
+        }
+        if let results::RootTestResult::Ignored = result {
+            ignored_tests.insert(test.name.clone());
+        }
 
         if opt.quiet == 0 {
             println!("{}", result.status());
@@ -72,7 +200,7 @@ fn run() -> Result<bool> {
 
         counts.update(&result);
         if !result.ok() {
-            fails.push((test.name, result));
+            fails.push((test.name, test.work_dir, result));
         }
     }
 
@@ -87,8 +215,11 @@ fn run() -> Result<bool> {
         }
         println!("failures:");
 
-        for (test, result) in fails {
+        for (test, test_work_dir, result) in fails {
             println!("\n--- {} ---", test.bold());
+            if !opt.cleanup && test_work_dir.exists() {
+                println!("root and actual output kept in {}", test_work_dir.display());
+            }
             result.print_details();
         }
     }
@@ -97,9 +228,77 @@ fn run() -> Result<bool> {
         println!("\n{}", counts);
     }
 
+    if let Some(teardown) = &opt.teardown {
+        run_suite_hook("teardown", teardown).context("run suite teardown")?;
+    }
+
     Ok(counts.tests_passed())
 }
 
+/// Make sure the work directory, where each test's previous output is removed, is neither in a
+/// test's directory nor holds one.
+fn check_work_dir(work_dir: &Path, tests: &[tests::RootTest]) -> Result<()> {
+    let work_dir = std::path::absolute(work_dir).context("resolve work directory")?;
+    // It's only created when the tests run
+    let (existing, rest) = work_dir
+        .ancestors()
+        .find_map(|ancestor| {
+            let rest = work_dir.strip_prefix(ancestor).ok()?;
+            Some((ancestor.canonicalize().ok()?, rest))
+        })
+        .context("resolve work directory")?;
+    let work_dir: PathBuf = existing.join(rest).components().collect();
+    for test in tests {
+        let dir = test
+            .dir
+            .canonicalize()
+            .with_context(|| format!("resolve directory of {}", test.name))?;
+        anyhow::ensure!(
+            !dir.starts_with(&work_dir) && !work_dir.starts_with(&dir),
+            "work directory {:?} overlaps test {} in {:?}",
+            work_dir,
+            test.name,
+            dir
+        );
+    }
+    Ok(())
+}
+
+/// Run a suite's setup or teardown command on the host. Its output is only shown if it fails.
+fn run_suite_hook(name: &str, run: &str) -> Result<()> {
+    debug!("Running suite {}: {}", name, run);
+    let output = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(run)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .context("run sh")?;
+    trace!("Suite {} output: {:#?}", name, output);
+    anyhow::ensure!(
+        output.status.success(),
+        "{} failed with {}:\n{}{}",
+        run,
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(())
+}
+
+/// Create a directory only the current user can access in the system's temporary directory.
+fn create_private_work_dir() -> Result<PathBuf> {
+    let template = std::env::temp_dir().join("roottest.XXXXXX");
+    let mut template = CString::new(template.into_os_string().into_vec())
+        .context("convert path to C string")?
+        .into_bytes_with_nul();
+    // SAFETY: template is a writable NUL-terminated string ending in XXXXXX
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
 pub(crate) fn display_error(error: anyhow::Error) {
     let mut chain = error.chain();
     let mut error_message = format!("Failed to {}\nCaused by:\n", chain.next().unwrap());
@@ -0,0 +1,286 @@
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use toml::Value;
+
+use crate::roots::RootSource;
+
+/// One combination of values of a test's `[matrix]` axes, in the order the axes are declared:
+///
+/// ```toml
+/// [matrix]
+/// flag = ["--fast", "--safe"]
+/// LANG = ["C", "de_DE"]
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Case {
+    values: Vec<(String, String)>,
+}
+
+impl Case {
+    /// Every combination of the axes' values. A test without axes has a single case.
+    pub fn expand(matrix: &toml::value::Table) -> Result<Vec<Case>> {
+        let mut cases = vec![Case::default()];
+        for (axis, values) in matrix {
+            let values = values
+                .as_array()
+                .with_context(|| format!("matrix axis {} is not an array", axis))?
+                .iter()
+                .map(|value| match value {
+                    Value::String(value) => Ok(value.clone()),
+                    Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => {
+                        Ok(value.to_string())
+                    }
+                    _ => anyhow::bail!("matrix axis {} has a value that isn't a scalar", axis),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            anyhow::ensure!(!values.is_empty(), "matrix axis {} has no values", axis);
+
+            cases = cases
+                .iter()
+                .flat_map(|case| {
+                    values.iter().map(move |value| {
+                        let mut case = case.clone();
+                        case.values.push((axis.clone(), value.clone()));
+                        case
+                    })
+                })
+                .collect();
+        }
+        Ok(cases)
+    }
+
+    /// The name of this case of `test`, like `test[flag=--fast,LANG=C]`.
+    pub fn name(&self, test: &str) -> String {
+        if self.values.is_empty() {
+            return test.to_string();
+        }
+        let values: Vec<_> = self
+            .values
+            .iter()
+            .map(|(axis, value)| format!("{}={}", axis, value))
+            .collect();
+        format!("{}[{}]", test, values.join(","))
+    }
+
+    /// A directory name for this case of `test`. Values can contain anything, but directories
+    /// can't contain `/`, and paths used in overlays can't contain `,`, `:` or `\\`.
+    pub fn dir_name(&self, test: &str) -> String {
+        self.name(test)
+            .chars()
+            .map(|c| match c {
+                '/' | ',' | ':' | '\\' | '%' => format!("%{:02X}", c as u32),
+                c => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// The axes and their values, which are also set as environment variables.
+    pub fn values(&self) -> &[(String, String)] {
+        &self.values
+    }
+
+    /// Replace the `{axis}` placeholders in `text` with this case's values, and `{{` and `}}` with
+    /// literal braces. Text is left alone in tests without axes.
+    pub fn substitute(&self, text: &str) -> String {
+        if self.values.is_empty() {
+            return text.to_string();
+        }
+        let mut substituted = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find(['{', '}']) {
+            substituted.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                substituted.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+            let value = rest[1..].find('}').and_then(|end| {
+                let axis = &rest[1..end + 1];
+                self.values
+                    .iter()
+                    .find(|(name, _)| name == axis)
+                    .map(|(_, value)| (end, value))
+            });
+            match value {
+                Some((end, value)) => {
+                    substituted.push_str(value);
+                    rest = &rest[end + 2..];
+                }
+                None => {
+                    substituted.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        substituted.push_str(rest);
+        substituted
+    }
+
+    /// Replace the placeholders in the commands of a `Roottest.toml` document: `run`, `setup`,
+    /// `teardown` and each step's `run`.
+    pub fn substitute_commands(&self, document: &mut Value) {
+        let table = match document.as_table_mut() {
+            Some(table) => table,
+            None => return,
+        };
+        for key in &["run", "setup", "teardown"] {
+            if let Some(value) = table.get_mut(*key) {
+                self.substitute_value(value);
+            }
+        }
+        if let Some(Value::Array(steps)) = table.get_mut("step") {
+            for step in steps {
+                if let Some(run) = step.get_mut("run") {
+                    self.substitute_value(run);
+                }
+            }
+        }
+    }
+
+    /// Replace the placeholders in all strings in `value`.
+    fn substitute_value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.substitute(text),
+            Value::Array(values) => values.iter_mut().for_each(|v| self.substitute_value(v)),
+            Value::Table(table) => table.iter_mut().for_each(|(_, v)| self.substitute_value(v)),
+            _ => {}
+        }
+    }
+
+    /// The variant of the file at `path` for this case, `<path>.<value>` for one of its values, or
+    /// `path` itself if there's none.
+    pub fn select(&self, path: &Path) -> Result<PathBuf> {
+        self.select_variant(path.to_owned(), |value| {
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(value);
+            Some(PathBuf::from(variant)).filter(|variant| variant.exists())
+        })
+    }
+
+    /// The name of the variant of the root `name` in `dir` for this case, a directory or archive
+    /// named `<name>.<value>` for one of its values, or `name` itself if there's none.
+    pub fn select_root(&self, dir: &Path, name: &str) -> Result<String> {
+        self.select_variant(name.to_string(), |value| {
+            let variant = format!("{}.{}", name, value);
+            RootSource::find_optional(dir, &variant).map(|_| variant)
+        })
+    }
+
+    fn select_variant<T: Debug>(
+        &self,
+        default: T,
+        variant: impl Fn(&str) -> Option<T>,
+    ) -> Result<T> {
+        let mut variants = self.values.iter().filter_map(|(_, value)| variant(value));
+        match (variants.next(), variants.next()) {
+            (Some(first), Some(second)) => {
+                anyhow::bail!("both {:?} and {:?} match the same case", first, second)
+            }
+            (Some(variant), None) => Ok(variant),
+            (None, _) => Ok(default),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cases(matrix: &str) -> Vec<Case> {
+        Case::expand(&toml::from_str(matrix).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn expands_every_combination_in_order() {
+        let names: Vec<_> = cases("flag = [\"--fast\", \"--safe\"]\nLANG = [\"C\", \"de\"]")
+            .iter()
+            .map(|case| case.name("test"))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "test[flag=--fast,LANG=C]",
+                "test[flag=--fast,LANG=de]",
+                "test[flag=--safe,LANG=C]",
+                "test[flag=--safe,LANG=de]",
+            ]
+        );
+        assert_eq!(cases("").len(), 1);
+        assert!(Case::expand(&toml::from_str("flag = []").unwrap()).is_err());
+    }
+
+    #[test]
+    fn substitutes_values_and_escaped_braces() {
+        let case = &cases("flag = [\"--fast\"]\nLANG = [\"C\"]")[0];
+        assert_eq!(
+            case.substitute("run {flag} ${LANG} ${{LANG}} {{flag}} {other} {"),
+            "run --fast $C ${LANG} {flag} {other} {"
+        );
+        assert_eq!(Case::default().substitute("{{flag}}"), "{{flag}}");
+    }
+
+    #[test]
+    fn substitutes_only_commands() {
+        let case = &cases("flag = [\"--fast\"]")[0];
+        let mut document: Value = toml::from_str(
+            r#"
+            cd = "/{flag}"
+            run = ["/program", "{flag}"]
+            setup = "touch {flag}"
+            teardown = { run = "rm {flag}", on_host = true }
+
+            [[step]]
+            run = "/program {flag}"
+            stdin = "{flag}"
+            "#,
+        )
+        .unwrap();
+        case.substitute_commands(&mut document);
+        let expected: Value = toml::from_str(
+            r#"
+            cd = "/{flag}"
+            run = ["/program", "--fast"]
+            setup = "touch --fast"
+            teardown = { run = "rm --fast", on_host = true }
+
+            [[step]]
+            run = "/program --fast"
+            stdin = "{flag}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(document, expected);
+    }
+
+    #[test]
+    fn escapes_directory_names() {
+        let case = &cases("path = [\"/usr/bin,x:y\\\\z%\"]")[0];
+        assert_eq!(
+            case.dir_name("a/b"),
+            "a%2Fb[path=%2Fusr%2Fbin%2Cx%3Ay%5Cz%25]"
+        );
+    }
+
+    #[test]
+    fn selects_root_variant_for_a_value() {
+        let dir = std::env::temp_dir().join(format!("roottest-matrix-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("root_after.C")).unwrap();
+        std::fs::create_dir_all(dir.join("root_after.de")).unwrap();
+        let cases = cases("LANG = [\"C\", \"fr\"]\nflag = [\"de\"]");
+
+        let selected: Vec<_> = cases
+            .iter()
+            .map(|case| case.select_root(&dir, "root_after").map_err(|_| ()))
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(selected, [Err(()), Ok("root_after.de".to_string())]);
+        assert_eq!(
+            Case::default().select_root(&dir, "root_after").unwrap(),
+            "root_after"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::results::FileNode;
+
+/// Per-path overrides for metadata that git can't store, loaded from the `metadata.toml` that sits
+/// next to a root. Each root gets its own table, keyed by the root's name:
+///
+/// ```toml
+/// [root_before."etc/shadow"]
+/// mode = "0640"
+/// uid = 0
+/// gid = 42
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MetadataManifest {
+    overrides: BTreeMap<PathBuf, MetadataOverride>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataOverride {
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Seconds since the epoch. Only applied when materializing a root, never compared.
+    mtime: Option<i64>,
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("invalid octal mode {:?}: {}", mode, e)))
+}
+
+impl MetadataManifest {
+    /// Load the table named `root_name` from the `metadata.toml` in `dir`.
+    /// A missing file or table means no overrides.
+    pub fn load(dir: &Path, root_name: &str) -> Result<MetadataManifest> {
+        let manifest_path = dir.join("metadata.toml");
+        if !manifest_path.exists() {
+            return Ok(MetadataManifest::default());
+        }
+
+        let mut tables: BTreeMap<String, BTreeMap<PathBuf, MetadataOverride>> =
+            toml::from_str(&std::fs::read_to_string(&manifest_path).context("read metadata.toml")?)
+                .context("parse metadata.toml")?;
+
+        MetadataManifest::from_overrides(tables.remove(root_name).unwrap_or_default())
+    }
+
+    pub fn from_overrides(
+        overrides: BTreeMap<PathBuf, MetadataOverride>,
+    ) -> Result<MetadataManifest> {
+        let overrides = overrides
+            .into_iter()
+            .map(|(path, o)| Ok((normalize_path(&path)?, o)))
+            .collect::<Result<_>>()?;
+        Ok(MetadataManifest { overrides })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overrides.is_empty()
+    }
+
+    /// Add the overrides from `other`, replacing these for the same paths.
+    pub fn extend(&mut self, other: MetadataManifest) {
+        self.overrides.extend(other.overrides);
+    }
+
+    /// Change the ownership of `path` inside `root`. If that isn't permitted, the ownership is
+    /// recorded in this manifest instead, so that it can be applied virtually later.
+    pub fn lchown_or_record(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> Result<()> {
+        match std::os::unix::fs::lchown(root.join(path), uid, gid) {
+            Ok(()) => {
+                // A lower layer may have recorded other ownership for the same path
+                self.overrides.remove(path);
+                Ok(())
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                debug!(
+                    "Not permitted to chown {:?}, tracking ownership virtually",
+                    path
+                );
+                self.overrides.insert(
+                    path.to_owned(),
+                    MetadataOverride {
+                        uid,
+                        gid,
+                        ..MetadataOverride::default()
+                    },
+                );
+                Ok(())
+            }
+            Err(e) => Err(e).with_context(|| format!("change ownership of {:?}", path)),
+        }
+    }
+
+    /// Apply the overrides to a materialized root on disk.
+    ///
+    /// Ownership can only be changed with sufficient privileges. Overrides that couldn't be applied
+    /// are recorded in `unapplied`, so that they can be applied virtually when loading the result.
+    pub fn apply(&self, root: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+        for (path, o) in &self.overrides {
+            let full_path = root.join(path);
+            let metadata = full_path
+                .symlink_metadata()
+                .with_context(|| format!("find {:?} listed in metadata.toml", path))?;
+
+            if o.uid.is_some() || o.gid.is_some() {
+                unapplied.lchown_or_record(root, path, o.uid, o.gid)?;
+            }
+
+            if let Some(mode) = o.mode {
+                if !metadata.file_type().is_symlink() {
+                    std::fs::set_permissions(&full_path, std::fs::Permissions::from_mode(mode))
+                        .with_context(|| format!("change mode of {:?}", path))?;
+                }
+            }
+
+            if let Some(mtime) = o.mtime {
+                set_mtime(&full_path, mtime).with_context(|| format!("set mtime of {:?}", path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the overrides to a loaded `FileNode` tree.
+    ///
+    /// Paths that don't exist in the tree are an error unless `missing_ok` is set, which is useful
+    /// when the program under test may have removed them.
+    pub fn overlay(&self, node: &mut FileNode, missing_ok: bool) -> Result<()> {
+        for (path, o) in &self.overrides {
+            let target = match node.find_mut(path) {
+                Some(target) => target,
+                None if missing_ok => continue,
+                None => anyhow::bail!("{:?} does not exist", path),
+            };
+
+            let is_symlink = matches!(target, FileNode::SymbolicLink { .. });
+            let permissions = target.permissions_mut();
+            if let Some(mode) = o.mode {
+                if !is_symlink {
+                    permissions.mode = (permissions.mode & !0o7777) | (mode & 0o7777);
+                }
+            }
+            if let Some(uid) = o.uid {
+                permissions.uid = uid;
+            }
+            if let Some(gid) = o.gid {
+                permissions.gid = gid;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Make a path relative to the root it's in, rejecting anything that escapes it.
+pub(crate) fn normalize_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(c) => normalized.push(c),
+            _ => anyhow::bail!("invalid path {:?}", path),
+        }
+    }
+    Ok(normalized)
+}
+
+pub(crate) fn set_mtime(path: &Path, mtime: i64) -> Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).context("convert path to C string")?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        },
+    ];
+    // SAFETY: path is a valid NUL-terminated string and times has the two entries utimensat reads
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::CStr;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::inject::Injection;
+
+/// Roottest's own directory in roots with mocks, left out of root comparisons
+const MOCK_DIR: &str = "/.roottest";
+/// Where shims go by default, put first in `PATH`
+const BIN_DIR: &str = "/.roottest/bin";
+
+/// A `[mock.NAME]` table, a command that answers with canned output instead of running:
+///
+/// ```toml
+/// [mock.systemctl]
+/// expected_calls = [["is-active", "sshd"], ["restart", "sshd"]]
+///
+/// [[mock.systemctl.case]]
+/// args = ["is-active", "sshd"]
+/// stdout = "inactive\n"
+/// status = 3
+/// ```
+///
+/// Calls that match no case get the table's own output and status.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockParams {
+    /// Where the shim goes in the root, instead of a directory first in `PATH`
+    path: Option<PathBuf>,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    #[serde(default)]
+    status: i32,
+    /// The arguments it should be called with, in order, if that's checked
+    expected_calls: Option<Vec<Vec<String>>>,
+    /// Whether calls read stdin until it's closed. Otherwise only what was already written to it
+    /// is read, so that the mock doesn't wait for input that never comes.
+    #[serde(default)]
+    reads_stdin: bool,
+    #[serde(default, rename = "case")]
+    cases: Vec<MockCase>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockCase {
+    args: Vec<String>,
+    #[serde(default)]
+    stdout: String,
+    #[serde(default)]
+    stderr: String,
+    #[serde(default)]
+    status: i32,
+}
+
+/// What a shim reads to know how to answer.
+#[derive(Debug, Deserialize, Serialize)]
+struct ShimConfig {
+    name: String,
+    mock: MockParams,
+}
+
+/// A logged call to a mock.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MockCall {
+    pub(crate) mock: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) stdin: String,
+    /// Last, since TOML tables have to come after plain values
+    pub(crate) environment: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CallLog<'a> {
+    call: &'a [MockCall],
+}
+
+impl MockParams {
+    fn respond(&self, args: &[String]) -> (&str, &str, i32) {
+        match self.cases.iter().find(|case| case.args == args) {
+            Some(case) => (&case.stdout, &case.stderr, case.status),
+            None => (&self.stdout, &self.stderr, self.status),
+        }
+    }
+}
+
+/// Put the directory with the shims of `mocks` first in `environment`'s `PATH`, or the host's if
+/// it has none.
+pub fn prepend_bin_dir(
+    mocks: &BTreeMap<String, MockParams>,
+    environment: &mut BTreeMap<String, String>,
+) {
+    if mocks.values().all(|mock| mock.path.is_some()) {
+        return;
+    }
+    let path = environment
+        .get("PATH")
+        .cloned()
+        .or_else(|| std::env::var("PATH").ok())
+        .unwrap_or_default();
+    environment.insert("PATH".to_string(), format!("{}:{}", BIN_DIR, path));
+}
+
+/// Install a shim for each of `mocks` in the root: a link to a copy of Roottest, which answers as
+/// the mock when it's run with the shim's name.
+pub fn install(mocks: &BTreeMap<String, MockParams>, injection: &mut Injection) -> Result<()> {
+    if mocks.is_empty() {
+        return Ok(());
+    }
+
+    let mock_dir = Path::new(MOCK_DIR);
+    injection.create_private_directory(mock_dir)?;
+    for (dir, mode) in &[("bin", 0o755), ("mock", 0o755), ("log", 0o777)] {
+        let dir = injection.dest().join(mock_dir.strip_prefix("/")?).join(dir);
+        std::fs::create_dir(&dir).with_context(|| format!("create {:?}", dir))?;
+        // Any user in the sandbox can call mocks
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(*mode))
+            .with_context(|| format!("change mode of {:?}", dir))?;
+    }
+    let exe = std::env::current_exe().context("find Roottest's executable")?;
+    let binary = injection
+        .copy_with_libraries(&mock_dir.join("roottest"), &exe, true, false)
+        .context("copy Roottest into root")?;
+
+    let mut shim_names = BTreeSet::new();
+    for (name, mock) in mocks {
+        anyhow::ensure!(
+            matches!(
+                Path::new(name).components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            ),
+            "invalid mock name {:?}",
+            name
+        );
+        let shim = match &mock.path {
+            Some(path) => path.clone(),
+            None => Path::new(BIN_DIR).join(name),
+        };
+        let shim_name = shim
+            .file_name()
+            .with_context(|| format!("{:?} is not a file name", shim))?
+            .to_string_lossy()
+            .into_owned();
+        anyhow::ensure!(
+            shim_names.insert(shim_name.clone()),
+            "two mocks are installed as {}",
+            shim_name
+        );
+        debug!("Installing mock {} as {:?}", name, shim);
+
+        injection
+            .link(&shim, &binary)
+            .with_context(|| format!("install mock {}", name))?;
+        let config = ShimConfig {
+            name: name.clone(),
+            mock: mock.clone(),
+        };
+        let config_path = injection
+            .dest()
+            .join(mock_dir.strip_prefix("/")?)
+            .join("mock")
+            .join(format!("{}.toml", shim_name));
+        std::fs::write(
+            &config_path,
+            toml::to_string(&config).context("serialize mock")?,
+        )
+        .with_context(|| format!("write {:?}", config_path))?;
+    }
+    Ok(())
+}
+
+/// The calls logged by the shims in the root whose top layer is `dest`, in order.
+pub fn read_calls(dest: &Path) -> Result<Vec<MockCall>> {
+    let log_dir = dest
+        .join(Path::new(MOCK_DIR).strip_prefix("/")?)
+        .join("log");
+    if !log_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut entries = std::fs::read_dir(&log_dir)
+        .context("read mock log")?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("read mock log")?;
+    entries.sort();
+    entries
+        .iter()
+        .map(|path| {
+            toml::from_str(&std::fs::read_to_string(path).context("read call")?)
+                .with_context(|| format!("parse call {:?}", path))
+        })
+        .collect()
+}
+
+/// Save `calls` as `mock_calls.toml` in `dir`.
+pub fn save_calls(dir: &Path, calls: &[MockCall]) -> Result<()> {
+    std::fs::write(
+        dir.join("mock_calls.toml"),
+        toml::to_string(&CallLog { call: calls }).context("serialize calls")?,
+    )
+    .context("save mock calls")
+}
+
+/// The calls to the mocks with `expected_calls`, and the expected ones, one per line and grouped
+/// by mock. `None` if no mock has `expected_calls`.
+pub fn compare_calls(
+    mocks: &BTreeMap<String, MockParams>,
+    calls: &[MockCall],
+) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut actual = String::new();
+    let mut expected = String::new();
+    let mut checked = false;
+    for (name, mock) in mocks {
+        let expected_calls = match &mock.expected_calls {
+            Some(expected_calls) => expected_calls,
+            None => continue,
+        };
+        checked = true;
+        for call in calls.iter().filter(|call| &call.mock == name) {
+            actual.push_str(&format_call(name, &call.args));
+        }
+        for args in expected_calls {
+            expected.push_str(&format_call(name, args));
+        }
+    }
+    if checked {
+        Some((actual.into_bytes(), expected.into_bytes()))
+    } else {
+        None
+    }
+}
+
+fn format_call(name: &str, args: &[String]) -> String {
+    let mut line = name.to_string();
+    for arg in args {
+        line.push(' ');
+        let plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+        if plain {
+            line.push_str(arg);
+        } else {
+            line.push_str(&format!("{:?}", arg));
+        }
+    }
+    line.push('\n');
+    line
+}
+
+/// If Roottest was run as a mock's shim, answer like the mock and return the exit status.
+pub fn run_as_shim() -> Option<i32> {
+    // SAFETY: getauxval is always safe to call, and AT_EXECFN is a NUL-terminated string that
+    // lives as long as the process if it's there
+    let executable = unsafe {
+        let executable = libc::getauxval(libc::AT_EXECFN) as *const libc::c_char;
+        if executable.is_null() {
+            return None;
+        }
+        CStr::from_ptr(executable).to_string_lossy().into_owned()
+    };
+    let shim_name = Path::new(&executable).file_name()?.to_string_lossy();
+    let config_path = Path::new(MOCK_DIR)
+        .join("mock")
+        .join(format!("{}.toml", shim_name));
+    if !config_path.is_file() {
+        return None;
+    }
+
+    Some(match respond(&config_path) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("roottest mock {}: {:#}", shim_name, e);
+            127
+        }
+    })
+}
+
+fn respond(config_path: &Path) -> Result<i32> {
+    let config: ShimConfig =
+        toml::from_str(&std::fs::read_to_string(config_path).context("read mock configuration")?)
+            .context("parse mock configuration")?;
+    let args: Vec<String> = std::env::args_os()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    let mut stdin = Vec::new();
+    if config.mock.reads_stdin {
+        // Logged even if it can't be read to the end
+        let _ = std::io::stdin().read_to_end(&mut stdin);
+    } else {
+        stdin = read_available_stdin();
+    }
+    let call = MockCall {
+        mock: config.name.clone(),
+        args,
+        stdin: String::from_utf8_lossy(&stdin).into_owned(),
+        environment: std::env::vars_os()
+            .map(|(key, value)| {
+                (
+                    key.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect(),
+    };
+    log_call(&call).context("log call")?;
+
+    let (stdout, stderr, status) = config.mock.respond(&call.args);
+    std::io::stdout().write_all(stdout.as_bytes())?;
+    std::io::stderr().write_all(stderr.as_bytes())?;
+    Ok(status)
+}
+
+/// What was already written to stdin, without waiting for more. Whatever can be read is logged,
+/// stdin may well be closed.
+fn read_available_stdin() -> Vec<u8> {
+    let mut stdin = Vec::new();
+    // SAFETY: fcntl only changes the flags of stdin, which are restored below
+    let flags = unsafe { libc::fcntl(0, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(0, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return stdin;
+    }
+    let mut buffer = [0; 4096];
+    loop {
+        match std::io::stdin().read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => stdin.extend_from_slice(&buffer[..read]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            // Including WouldBlock, once nothing more was written
+            Err(_) => break,
+        }
+    }
+    // The description of stdin is shared with the processes that gave it to the mock
+    // SAFETY: fcntl only restores the flags of stdin
+    unsafe { libc::fcntl(0, libc::F_SETFL, flags) };
+    stdin
+}
+
+/// Log `call` in the next free numbered file, so that calls are in order even when mocks call
+/// each other.
+fn log_call(call: &MockCall) -> Result<()> {
+    let log_dir = Path::new(MOCK_DIR).join("log");
+    let contents = toml::to_string(call).context("serialize call")?;
+    for i in 0.. {
+        let path = log_dir.join(format!("{:06}.toml", i));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => return file.write_all(contents.as_bytes()).context("write call"),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("create {:?}", path)),
+        }
+    }
+    unreachable!("ran out of log file names")
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use crate::results::{FileNode, Permissions};
+
+/// Marks a directory as opaque in layers written by fuse-overlayfs without privileges
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_XATTRS: &[&[u8]] = &[b"user.overlay.opaque\0", b"trusted.overlay.opaque\0"];
+
+/// A copy-on-write root: the read-only `lower` layers, from the top down, with the program's
+/// changes going to `upper`.
+#[derive(Debug)]
+pub struct Overlay {
+    pub(crate) lower: Vec<PathBuf>,
+    pub(crate) upper: PathBuf,
+    pub(crate) work: PathBuf,
+}
+
+impl Overlay {
+    /// The options for mounting the overlay, shared by overlayfs and fuse-overlayfs.
+    pub fn mount_options(&self) -> Result<String> {
+        let lower = self
+            .lower
+            .iter()
+            .map(|layer| mount_option_path(layer))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.join(":"),
+            mount_option_path(&self.upper)?,
+            mount_option_path(&self.work)?,
+        ))
+    }
+
+    /// The paths the program created, changed or removed, without the directories that only
+    /// had to be copied up to hold them.
+    pub fn changed_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        self.collect_changed_paths(Path::new(""), &mut changed)?;
+        Ok(changed)
+    }
+
+    fn collect_changed_paths(&self, path: &Path, changed: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in read_dir_sorted(&self.upper.join(path))? {
+            let (name, metadata) = entry;
+            if name == OPAQUE_MARKER {
+                continue;
+            }
+            if let Some(target) = whiteout_target(&name, &metadata) {
+                changed.push(path.join(target));
+                continue;
+            }
+
+            let child = path.join(&name);
+            let existed_as_directory = self.lower.iter().any(|layer| layer.join(&child).is_dir());
+            if metadata.is_dir() {
+                if !existed_as_directory || is_opaque(&self.upper.join(&child))? {
+                    changed.push(child.clone());
+                }
+                self.collect_changed_paths(&child, changed)?;
+            } else {
+                changed.push(child);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mounts an overlay with fuse-overlayfs on the host, for backends that can't mount it
+/// themselves. Unmounted when dropped.
+#[derive(Debug)]
+pub struct FuseMount {
+    mount_point: PathBuf,
+}
+
+impl FuseMount {
+    pub fn mount(overlay: &Overlay, mount_point: &Path) -> Result<FuseMount> {
+        let output = Command::new("fuse-overlayfs")
+            .arg("-o")
+            .arg(overlay.mount_options()?)
+            .arg(mount_point)
+            .output()
+            .context("run fuse-overlayfs")?;
+        anyhow::ensure!(
+            output.status.success(),
+            "fuse-overlayfs failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(FuseMount {
+            mount_point: mount_point.to_owned(),
+        })
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        let unmounted = ["fusermount3", "fusermount"].iter().any(|program| {
+            Command::new(program)
+                .arg("-u")
+                .arg(&self.mount_point)
+                .status()
+                .is_ok_and(|status| status.success())
+        });
+        if !unmounted {
+            error!("Failed to unmount overlay at {:?}", self.mount_point);
+        }
+    }
+}
+
+/// Load the root made of overlay `layers`, from the top down, the way the overlay shows it.
+pub fn load_layers(layers: &[PathBuf]) -> Result<FileNode> {
+    let mut layers = layers.iter().rev();
+    let bottom = layers.next().expect("at least one layer");
+    let mut node = FileNode::load_from(bottom)?;
+    for layer in layers {
+        let metadata = layer
+            .symlink_metadata()
+            .with_context(|| format!("get metadata of {:?}", layer))?;
+        *node.permissions_mut() = permissions(&metadata);
+        apply_layer(&mut node, layer, Path::new(""))
+            .with_context(|| format!("apply layer {:?}", layer))?;
+    }
+    Ok(node)
+}
+
+fn apply_layer(node: &mut FileNode, layer: &Path, path: &Path) -> Result<()> {
+    for (name, metadata) in read_dir_sorted(&layer.join(path))? {
+        if name == OPAQUE_MARKER {
+            continue;
+        }
+        if let Some(target) = whiteout_target(&name, &metadata) {
+            node.remove(&path.join(target));
+            continue;
+        }
+
+        let child = path.join(&name);
+        let full_path = layer.join(&child);
+        if metadata.is_dir() {
+            let merged = matches!(node.find_mut(&child), Some(FileNode::Directory { .. }))
+                && !is_opaque(&full_path)?;
+            if !merged {
+                node.remove(&child);
+            }
+            node.insert(
+                &child,
+                FileNode::Directory {
+                    children: BTreeMap::new(),
+                    permissions: permissions(&metadata),
+                },
+            )?;
+            apply_layer(node, layer, &child)?;
+        } else {
+            node.remove(&child);
+            node.insert(&child, FileNode::load_from(&full_path)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// The name of the entry that `name` removes from the layers below, if it's a whiteout.
+pub fn whiteout_target(name: &OsStr, metadata: &Metadata) -> Option<OsString> {
+    if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+        Some(name.to_owned())
+    } else {
+        name.as_bytes()
+            .strip_prefix(WHITEOUT_PREFIX.as_bytes())
+            .filter(|target| !target.is_empty() && name != OsStr::new(OPAQUE_MARKER))
+            .map(|target| OsStr::from_bytes(target).to_owned())
+    }
+}
+
+/// Whether the directory at `path` hides the contents of the directories below it.
+pub fn is_opaque(path: &Path) -> Result<bool> {
+    if path.join(OPAQUE_MARKER).symlink_metadata().is_ok() {
+        return Ok(true);
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("convert path to C string")?;
+    for name in OPAQUE_XATTRS {
+        let mut value = [0u8; 1];
+        // SAFETY: both strings are NUL-terminated and value is as long as the size passed
+        let len = unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                name.as_ptr().cast(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if len == 1 && value[0] == b'y' {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn permissions(metadata: &Metadata) -> Permissions {
+    Permissions {
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }
+}
+
+fn read_dir_sorted(path: &Path) -> Result<Vec<(OsString, Metadata)>> {
+    let mut entries = std::fs::read_dir(path)
+        .with_context(|| format!("read directory {:?}", path))?
+        .map(|entry| {
+            let entry = entry.with_context(|| format!("read directory {:?}", path))?;
+            let metadata = entry
+                .path()
+                .symlink_metadata()
+                .with_context(|| format!("get metadata of {:?}", entry.path()))?;
+            Ok((entry.file_name(), metadata))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+fn mount_option_path(path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("resolve {:?}", path))?;
+    let path = path
+        .to_str()
+        .with_context(|| format!("{:?} is not valid UTF-8", path))?;
+    anyhow::ensure!(
+        !path.contains([':', ',', '\\']),
+        "{:?} can't be used in an overlay, it contains ':', ',' or '\\'",
+        path
+    );
+    Ok(path.to_string())
+}
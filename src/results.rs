@@ -1,24 +1,64 @@
 use std::collections::BTreeMap;
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 
 use anyhow::{Context, Result};
-use crossterm::style::Colorize;
+use crossterm::style::{Colorize, Styler};
 
 use crate::difference::FileNodeDiff;
+use crate::tests::CommandOutput;
 
 #[derive(Debug)]
 pub enum RootTestResult {
     Ok,
     Ignored,
+    Skipped {
+        prerequisite: String,
+    },
+    /// The setup or teardown hook failed, so the test couldn't be run or checked
+    SetupError {
+        hook: &'static str,
+        /// Its exit status, or why it couldn't be started
+        failure: String,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
     Failed {
-        stdout: TestFieldComparison<Vec<u8>, Vec<u8>>,
-        stderr: TestFieldComparison<Vec<u8>, Vec<u8>>,
-        status: TestFieldComparison<i32, i32>,
-        root: TestFieldComparison<FileNode, FileNode>,
+        steps: Vec<StepResult>,
+        /// The steps after a failed one, which weren't run
+        steps_not_run: usize,
+        /// The final root, only compared when all steps ran
+        root: Option<TestFieldComparison<FileNode, FileNode>>,
+        /// The calls to mocks with expected calls, only compared when all steps ran
+        calls: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+        /// The paths the program changed, known for copy-on-write roots
+        changed_paths: Option<Vec<PathBuf>>,
     },
 }
 
+#[derive(Debug)]
+pub struct StepResult {
+    name: Option<String>,
+    /// Unless only the combined output is checked
+    stdout: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+    stderr: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+    /// Both streams marked line by line, if the step checks them together
+    output: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+    /// What the terminal showed at the end, if the step checks it
+    screen: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+    /// The actions of the step's interaction that were done, if it has one
+    interaction: Option<TestFieldComparison<Vec<u8>, Vec<u8>>>,
+    /// Unless the step's program couldn't be started
+    status: Option<TestFieldComparison<ExitStatus, i32>>,
+    /// Why the step's program couldn't be started, if it couldn't
+    not_started: Option<String>,
+    /// The `[limits]` entry that killed the step's program, if one did
+    limit: Option<&'static str>,
+    /// The root after the step, if the step checks it
+    root: Option<TestFieldComparison<FileNode, FileNode>>,
+}
+
 #[derive(Debug)]
 pub enum TestFieldComparison<L, R> {
     Identical,
@@ -39,6 +79,19 @@ pub enum FileNode {
         target: PathBuf,
         permissions: Permissions,
     },
+    Special {
+        kind: SpecialKind,
+        device: u64,
+        permissions: Permissions,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialKind {
+    CharacterDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -52,46 +105,25 @@ pub struct Permissions {
 pub struct Counts {
     ok: usize,
     failed: usize,
+    setup_errors: usize,
+    skipped: usize,
     ignored: usize,
 }
 
 impl RootTestResult {
     pub fn new(
-        test: &crate::tests::RootTest,
-        output: std::process::Output,
+        steps: Vec<StepResult>,
+        steps_not_run: usize,
+        roots: Option<(FileNode, FileNode)>,
+        calls: Option<(Vec<u8>, Vec<u8>)>,
+        changed_paths: Option<Vec<PathBuf>>,
     ) -> Result<RootTestResult> {
-        let status = output.status.code().expect("status code of process");
-        let status = if status == test.params.expected_status {
-            TestFieldComparison::Identical
-        } else {
-            TestFieldComparison::Differs(status, test.params.expected_status)
-        };
-
-        let stdout = if output.stdout == test.expected_stdout {
-            TestFieldComparison::Identical
-        } else {
-            TestFieldComparison::Differs(output.stdout, test.expected_stdout.clone())
-        };
-
-        let stderr = if output.stderr == test.expected_stderr {
-            TestFieldComparison::Identical
-        } else {
-            TestFieldComparison::Differs(output.stderr, test.expected_stderr.clone())
-        };
-
-        let root = FileNode::load_from(&test.root).context("load actual root")?;
-        let root_after = FileNode::load_from(&test.root_after).context("load expected root")?;
-        let root = if root == root_after {
-            TestFieldComparison::Identical
-        } else {
-            TestFieldComparison::Differs(root, root_after)
-        };
-
         Ok(RootTestResult::Failed {
-            status,
-            stdout,
-            stderr,
-            root,
+            steps,
+            steps_not_run,
+            root: roots.map(|(root, root_after)| compare(root, root_after)),
+            calls: calls.map(|(actual, expected)| compare(actual, expected)),
+            changed_paths,
         }
         .upgrade_to_ok())
     }
@@ -100,14 +132,14 @@ impl RootTestResult {
         match self {
             RootTestResult::Ok => RootTestResult::Ok,
             RootTestResult::Failed {
-                status,
-                stdout,
-                stderr,
-                root,
-            } if status.identical()
-                && stdout.identical()
-                && stderr.identical()
-                && root.identical() =>
+                steps,
+                steps_not_run: 0,
+                root: Some(root),
+                calls,
+                ..
+            } if steps.iter().all(StepResult::ok)
+                && root.identical()
+                && calls.as_ref().is_none_or(TestFieldComparison::identical) =>
             {
                 RootTestResult::Ok
             }
@@ -119,11 +151,19 @@ impl RootTestResult {
         matches!(self, RootTestResult::Ok | RootTestResult::Ignored)
     }
 
+    pub fn skipped(prerequisite: &str) -> RootTestResult {
+        RootTestResult::Skipped {
+            prerequisite: prerequisite.to_string(),
+        }
+    }
+
     pub fn status(&self) -> crossterm::style::StyledContent<&'static str> {
         match self {
             RootTestResult::Ok => "ok".green(),
             RootTestResult::Failed { .. } => "FAILED".red(),
             RootTestResult::Ignored => "ignored".grey(),
+            RootTestResult::Skipped { .. } => "skipped".yellow(),
+            RootTestResult::SetupError { .. } => "setup error".magenta(),
         }
     }
 
@@ -131,7 +171,9 @@ impl RootTestResult {
         match self {
             RootTestResult::Ok => ".".white(),
             RootTestResult::Failed { .. } => "F".red(),
-            RootTestResult::Ignored { .. } => "I".grey(),
+            RootTestResult::Ignored => "I".grey(),
+            RootTestResult::Skipped { .. } => "S".yellow(),
+            RootTestResult::SetupError { .. } => "E".magenta(),
         }
     }
 
@@ -139,34 +181,203 @@ impl RootTestResult {
         match self {
             RootTestResult::Ok => panic!("printing details of ok result"),
             RootTestResult::Ignored => panic!("printing details of ignored result"),
-            RootTestResult::Failed {
+            RootTestResult::Skipped { prerequisite } => {
+                println!(
+                    "skipped: prerequisite {} did not pass",
+                    prerequisite.yellow()
+                );
+            }
+            RootTestResult::SetupError {
+                hook,
+                failure,
                 stdout,
                 stderr,
-                status,
+            } => {
+                println!("{} failed: {}", hook, failure.magenta());
+                for (output, name) in [(stdout, "stdout"), (stderr, "stderr")] {
+                    if !output.is_empty() {
+                        println!("{} {}:", hook, name);
+                        print!("{}", String::from_utf8_lossy(&output));
+                    }
+                }
+            }
+            RootTestResult::Failed {
+                steps,
+                steps_not_run,
                 root,
+                calls,
+                changed_paths,
             } => {
-                if let TestFieldComparison::Differs(actual, expected) = status {
+                for step in steps {
+                    if !step.ok() {
+                        step.print_details();
+                    }
+                }
+                if steps_not_run > 0 {
                     println!(
-                        "status differs: actual {}, expected {}",
-                        actual.to_string().red(),
-                        expected.to_string().green(),
+                        "{} later {} not run",
+                        steps_not_run,
+                        if steps_not_run == 1 {
+                            "step was"
+                        } else {
+                            "steps were"
+                        }
                     );
                 }
 
-                output_diff(stdout, "stdout");
-                output_diff(stderr, "stderr");
+                if let Some(calls) = calls {
+                    output_diff(calls, "mock call log");
+                }
+
+                if let Some(TestFieldComparison::Differs(actual, expected)) = root {
+                    print_root_diff(actual, expected);
 
-                if let TestFieldComparison::Differs(actual, expected) = root {
-                    let diff = FileNodeDiff::from_file_nodes(actual, expected);
-                    println!("root directory differs:");
-                    trace!("FileNodeDiff: {:#?}", diff);
-                    diff.print(0);
+                    if let Some(changed_paths) = changed_paths {
+                        println!("paths changed by the program:");
+                        for path in changed_paths {
+                            println!("  {}", path.display().to_string().blue());
+                        }
+                    }
                 }
             }
         }
     }
 }
 
+impl StepResult {
+    pub fn new(
+        step: &crate::steps::Step,
+        output: CommandOutput,
+        roots: Option<(FileNode, FileNode)>,
+    ) -> StepResult {
+        let status = if output.status.code() == Some(step.expected_status) {
+            TestFieldComparison::Identical
+        } else {
+            TestFieldComparison::Differs(output.status, step.expected_status)
+        };
+        let compare_optional = |actual: Option<Vec<u8>>, expected: &Option<Vec<u8>>| {
+            actual
+                .zip(expected.clone())
+                .map(|(actual, expected)| compare(actual, expected))
+        };
+        StepResult {
+            name: step.name.clone(),
+            status: Some(status),
+            not_started: None,
+            limit: output.limit,
+            stdout: compare_optional(Some(output.stdout), &step.expected_stdout),
+            stderr: compare_optional(Some(output.stderr), &step.expected_stderr),
+            output: compare_optional(output.combined, &step.expected_output),
+            screen: compare_optional(output.screen, &step.expected_screen),
+            interaction: compare_optional(
+                output.transcript,
+                &step
+                    .interaction
+                    .as_ref()
+                    .map(|interaction| interaction.expected_transcript()),
+            ),
+            root: roots.map(|(root, root_after)| compare(root, root_after)),
+        }
+    }
+
+    /// The result of a step whose program couldn't be started, because of `error`.
+    pub fn not_started(step: &crate::steps::Step, error: String) -> StepResult {
+        StepResult {
+            name: step.name.clone(),
+            status: None,
+            not_started: Some(error),
+            limit: None,
+            stdout: None,
+            stderr: None,
+            output: None,
+            screen: None,
+            interaction: None,
+            root: None,
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.not_started.is_none()
+            && self
+                .status
+                .as_ref()
+                .is_none_or(TestFieldComparison::identical)
+            && [&self.stdout, &self.stderr, &self.output]
+                .iter()
+                .all(|output| output.as_ref().is_none_or(TestFieldComparison::identical))
+            && self
+                .screen
+                .as_ref()
+                .is_none_or(TestFieldComparison::identical)
+            && self
+                .interaction
+                .as_ref()
+                .is_none_or(TestFieldComparison::identical)
+            && self
+                .root
+                .as_ref()
+                .is_none_or(TestFieldComparison::identical)
+    }
+
+    fn print_details(self) {
+        if let Some(name) = &self.name {
+            println!("step {} failed:", name.as_str().bold());
+        }
+
+        if let Some(error) = &self.not_started {
+            println!("{}", error.as_str().red());
+        }
+
+        if let Some(TestFieldComparison::Differs(actual, expected)) = self.status {
+            let actual = match actual.code() {
+                Some(code) => code.to_string(),
+                None => format!("killed by {}", actual),
+            };
+            println!(
+                "status differs: actual {}, expected {}",
+                actual.red(),
+                expected.to_string().green(),
+            );
+            if let Some(limit) = self.limit {
+                println!("the {} limit was hit", limit.bold());
+            }
+        }
+
+        if let Some(interaction) = self.interaction {
+            output_diff(interaction, "interaction");
+        }
+        for (output, name) in [
+            (self.stdout, "stdout"),
+            (self.stderr, "stderr"),
+            (self.output, "output"),
+            (self.screen, "screen"),
+        ] {
+            if let Some(output) = output {
+                output_diff(output, name);
+            }
+        }
+
+        if let Some(TestFieldComparison::Differs(actual, expected)) = self.root {
+            print_root_diff(actual, expected);
+        }
+    }
+}
+
+fn compare<T: PartialEq>(actual: T, expected: T) -> TestFieldComparison<T, T> {
+    if actual == expected {
+        TestFieldComparison::Identical
+    } else {
+        TestFieldComparison::Differs(actual, expected)
+    }
+}
+
+fn print_root_diff(actual: FileNode, expected: FileNode) {
+    let diff = FileNodeDiff::from_file_nodes(actual, expected);
+    println!("root directory differs:");
+    trace!("FileNodeDiff: {:#?}", diff);
+    diff.print(0);
+}
+
 fn output_diff(output: TestFieldComparison<Vec<u8>, Vec<u8>>, name: &str) {
     if let TestFieldComparison::Differs(actual, expected) = output {
         println!(
@@ -177,12 +388,7 @@ fn output_diff(output: TestFieldComparison<Vec<u8>, Vec<u8>>, name: &str) {
         );
         match (String::from_utf8(actual), String::from_utf8(expected)) {
             (Ok(actual), Ok(expected)) => {
-                let diff: Vec<_> = diff::lines(&actual, &expected)
-                    .into_iter()
-                    .map(crate::difference::to_owned_diff_result)
-                    .collect();
-                assert!(crate::difference::diff_nonempty(&diff));
-                crate::difference::print_diff(diff, 3);
+                crate::difference::print_diff(crate::difference::text_diff(&actual, &expected), 3);
             }
             (_, _) => {
                 println!(
@@ -200,14 +406,14 @@ fn output_diff(output: TestFieldComparison<Vec<u8>, Vec<u8>>, name: &str) {
 
 impl std::fmt::Display for Counts {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let result = if self.failed == 0 {
+        let result = if self.tests_passed() {
             "ok".green()
         } else {
             "FAILED".red()
         };
         write!(
             f,
-            "roottest result: {}. {} ok, {} failed, and {} ignored.",
+            "roottest result: {}. {} ok, {} failed, {} setup errors, {} skipped, and {} ignored.",
             result,
             self.ok.to_string().green(),
             if self.failed == 0 {
@@ -215,26 +421,37 @@ impl std::fmt::Display for Counts {
             } else {
                 self.failed.to_string().red()
             },
+            self.setup_errors.to_string().magenta(),
+            self.skipped.to_string().yellow(),
             self.ignored.to_string().grey(),
         )
     }
 }
 
 impl FileNode {
-    fn load_from(path: impl AsRef<Path>) -> Result<FileNode> {
+    pub(crate) fn load_from(path: impl AsRef<Path>) -> Result<FileNode> {
         let path = path.as_ref();
+        let metadata = path
+            .symlink_metadata()
+            .with_context(|| format!("get metadata of {:?}", path))?;
         if let Ok(target) = path.read_link() {
             Ok(FileNode::SymbolicLink {
                 target,
                 permissions: Permissions::load_from(path).context("load path's permissions")?,
             })
+        } else if let Some(kind) = SpecialKind::from_file_type(metadata.file_type()) {
+            Ok(FileNode::Special {
+                kind,
+                device: metadata.rdev(),
+                permissions: Permissions::load_from(path).context("load path's permissions")?,
+            })
         } else if path.is_dir() {
             let children: Result<BTreeMap<PathBuf, FileNode>> = path
                 .read_dir()
                 .context("read dir")?
                 .map(|e| {
                     e.context("get dir entry").and_then(|e| {
-                        FileNode::load_from(&e.path())
+                        FileNode::load_from(e.path())
                             .map(|r| (PathBuf::from(e.path().file_name().expect("file name")), r))
                     })
                 })
@@ -243,7 +460,6 @@ impl FileNode {
                 children: children?,
                 permissions: Permissions::load_from(path).context("load path's permissions")?,
             })
-        // TODO: is there a bug hiding here if file doesn't exist?
         } else {
             Ok(FileNode::File {
                 contents: std::fs::read(path)
@@ -253,16 +469,180 @@ impl FileNode {
         }
     }
 
+    pub(crate) fn find_mut(&mut self, path: &Path) -> Option<&mut FileNode> {
+        let mut node = self;
+        for component in path.iter() {
+            node = match node {
+                FileNode::Directory { children, .. } => children.get_mut(Path::new(component))?,
+                _ => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// Put `node` at `path`, creating missing parents the way extracting or writing it would.
+    /// A directory that already exists keeps its children and only takes the new permissions.
+    pub(crate) fn insert(&mut self, path: &Path, node: FileNode) -> Result<()> {
+        let mut current = self;
+        for component in path.iter() {
+            current = match current {
+                FileNode::Directory { children, .. } => children
+                    .entry(PathBuf::from(component))
+                    .or_insert_with(|| FileNode::Directory {
+                        children: BTreeMap::new(),
+                        permissions: Permissions::for_new(libc::S_IFDIR),
+                    }),
+                other => anyhow::bail!("parent is a {}, not a directory", other.node_type()),
+            };
+        }
+
+        match (current, node) {
+            (
+                FileNode::Directory { permissions, .. },
+                FileNode::Directory {
+                    permissions: new_permissions,
+                    ..
+                },
+            ) => *permissions = new_permissions,
+            (current, node) => *current = node,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, path: &Path) -> Option<FileNode> {
+        let parent = match path.parent() {
+            Some(parent) => self.find_mut(parent)?,
+            None => return None,
+        };
+        match parent {
+            FileNode::Directory { children, .. } => children.remove(Path::new(path.file_name()?)),
+            _ => None,
+        }
+    }
+
+    /// Lay `upper` over this node overlay-style: directories are merged, anything else is replaced.
+    /// Directories that exist in both take the upper permissions unless `keep_permissions` is set.
+    pub(crate) fn merge(&mut self, upper: FileNode, keep_permissions: bool) {
+        match (self, upper) {
+            (
+                FileNode::Directory {
+                    children,
+                    permissions,
+                },
+                FileNode::Directory {
+                    children: upper_children,
+                    permissions: upper_permissions,
+                },
+            ) => {
+                if !keep_permissions {
+                    *permissions = upper_permissions;
+                }
+                for (name, upper_child) in upper_children {
+                    match children.get_mut(&name) {
+                        Some(child) => child.merge(upper_child, keep_permissions),
+                        None => {
+                            children.insert(name, upper_child);
+                        }
+                    }
+                }
+            }
+            (lower, upper) => *lower = upper,
+        }
+    }
+
+    pub(crate) fn permissions_mut(&mut self) -> &mut Permissions {
+        match self {
+            FileNode::File { permissions, .. }
+            | FileNode::Directory { permissions, .. }
+            | FileNode::SymbolicLink { permissions, .. }
+            | FileNode::Special { permissions, .. } => permissions,
+        }
+    }
+
+    /// Show files owned by `uid` and `gid` as owned by root, the way a command running in a user
+    /// namespace that maps them to root sees them.
+    pub(crate) fn map_owner_to_root(&mut self, uid: u32, gid: u32) {
+        let permissions = self.permissions_mut();
+        if permissions.uid == uid {
+            permissions.uid = 0;
+        }
+        if permissions.gid == gid {
+            permissions.gid = 0;
+        }
+        if let FileNode::Directory { children, .. } = self {
+            for child in children.values_mut() {
+                child.map_owner_to_root(uid, gid);
+            }
+        }
+    }
+
     pub(crate) fn node_type(&self) -> &'static str {
         match self {
             FileNode::File { .. } => "file",
             FileNode::Directory { .. } => "directory",
             FileNode::SymbolicLink { .. } => "symbolic link",
+            FileNode::Special { kind, .. } => kind.name(),
+        }
+    }
+}
+
+impl SpecialKind {
+    pub(crate) fn from_file_type(file_type: std::fs::FileType) -> Option<SpecialKind> {
+        if file_type.is_char_device() {
+            Some(SpecialKind::CharacterDevice)
+        } else if file_type.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else if file_type.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialKind::Socket)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn file_type_bits(self) -> u32 {
+        match self {
+            SpecialKind::CharacterDevice => libc::S_IFCHR,
+            SpecialKind::BlockDevice => libc::S_IFBLK,
+            SpecialKind::Fifo => libc::S_IFIFO,
+            SpecialKind::Socket => libc::S_IFSOCK,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            SpecialKind::CharacterDevice => "character device",
+            SpecialKind::BlockDevice => "block device",
+            SpecialKind::Fifo => "fifo",
+            SpecialKind::Socket => "socket",
         }
     }
 }
 
 impl Permissions {
+    /// What a newly created node of the given type gets: the current user's ownership and a mode
+    /// after the umask.
+    pub(crate) fn for_new(file_type: u32) -> Permissions {
+        let base = if file_type == libc::S_IFDIR {
+            0o777
+        } else {
+            0o666
+        };
+        // SAFETY: umask can't fail. It can only be read by setting it, so restore it right away.
+        let umask = unsafe {
+            let umask = libc::umask(0o022);
+            libc::umask(umask);
+            umask
+        };
+        Permissions {
+            mode: file_type | (base & !umask),
+            // SAFETY: getuid and getgid are always successful
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
     fn load_from(path: impl AsRef<Path>) -> Result<Permissions> {
         let path = path.as_ref();
         let metadata = path.symlink_metadata().context("get metadata")?;
@@ -286,10 +666,12 @@ impl Counts {
             RootTestResult::Ok => self.ok += 1,
             RootTestResult::Ignored => self.ignored += 1,
             RootTestResult::Failed { .. } => self.failed += 1,
+            RootTestResult::Skipped { .. } => self.skipped += 1,
+            RootTestResult::SetupError { .. } => self.setup_errors += 1,
         }
     }
 
     pub fn tests_passed(&self) -> bool {
-        self.failed == 0
+        self.failed == 0 && self.setup_errors == 0
     }
 }
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::copy::{copy_layer, copy_tree, remove_existing};
+use crate::metadata::{normalize_path, MetadataManifest};
+use crate::overlay;
+use crate::results::{FileNode, Permissions, SpecialKind};
+
+const ARCHIVE_EXTENSIONS: &[&str] = &["tar", "tar.gz", "tgz", "tar.zst"];
+
+/// A root together with the `metadata.toml` overrides that belong to it.
+#[derive(Debug)]
+pub struct Root {
+    pub(crate) source: RootSource,
+    pub(crate) metadata: MetadataManifest,
+}
+
+/// Where the contents of a root come from.
+#[derive(Debug)]
+pub enum RootSource {
+    Directory(PathBuf),
+    Archive(PathBuf),
+}
+
+impl RootSource {
+    /// Find the root called `name` in `dir`, either as a directory or as an archive.
+    pub fn find(dir: &Path, name: &str) -> Result<RootSource> {
+        RootSource::find_optional(dir, name).with_context(|| {
+            format!(
+                "neither directory {:?} nor an archive {}.{{{}}} exists",
+                dir.join(name),
+                name,
+                ARCHIVE_EXTENSIONS.join(",")
+            )
+        })
+    }
+
+    pub fn find_optional(dir: &Path, name: &str) -> Option<RootSource> {
+        let directory = dir.join(name);
+        if directory.is_dir() {
+            return Some(RootSource::Directory(directory));
+        }
+
+        ARCHIVE_EXTENSIONS
+            .iter()
+            .map(|extension| dir.join(format!("{}.{}", name, extension)))
+            .find(|archive| archive.is_file())
+            .map(RootSource::Archive)
+    }
+
+    fn from_path(path: &Path) -> Result<RootSource> {
+        if path.is_dir() {
+            Ok(RootSource::Directory(path.to_owned()))
+        } else if archive_name(path).is_some() {
+            Ok(RootSource::Archive(path.to_owned()))
+        } else {
+            anyhow::bail!("{:?} is neither a directory nor an archive", path)
+        }
+    }
+
+    /// Copy the root's contents into `dest`, over anything that's already there.
+    ///
+    /// Ownership that can't be applied for lack of privileges is recorded in `unapplied`.
+    pub fn materialize(&self, dest: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+        match self {
+            RootSource::Directory(path) => copy_tree(path, dest, unapplied),
+            RootSource::Archive(path) => extract_archive(path, dest, unapplied)
+                .with_context(|| format!("extract {:?}", path)),
+        }
+    }
+
+    pub fn load(&self) -> Result<FileNode> {
+        match self {
+            RootSource::Directory(path) => FileNode::load_from(path),
+            RootSource::Archive(path) => {
+                load_archive(path).with_context(|| format!("load {:?}", path))
+            }
+        }
+    }
+}
+
+impl Root {
+    /// Find the root called `name` in `dir`, either as a directory or as an archive.
+    pub fn find(dir: &Path, name: &str) -> Result<Root> {
+        Ok(Root {
+            source: RootSource::find(dir, name)?,
+            metadata: MetadataManifest::load(dir, name)
+                .with_context(|| format!("load metadata for {}", name))?,
+        })
+    }
+
+    pub fn find_optional(dir: &Path, name: &str) -> Result<Option<Root>> {
+        match RootSource::find_optional(dir, name) {
+            Some(_) => Root::find(dir, name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Load the root at `path`, a directory or an archive. Its metadata is the table named after it
+    /// in the `metadata.toml` next to it.
+    pub fn from_path(path: &Path) -> Result<Root> {
+        let source = RootSource::from_path(path)?;
+        let file_name = path
+            .file_name()
+            .context("get name of root")?
+            .to_string_lossy();
+        let name = archive_name(path).unwrap_or(&file_name);
+        let metadata = match path.parent() {
+            Some(parent) => MetadataManifest::load(parent, name)
+                .with_context(|| format!("load metadata for {}", name))?,
+            None => MetadataManifest::default(),
+        };
+        Ok(Root { source, metadata })
+    }
+
+    pub fn materialize(&self, dest: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+        self.source.materialize(dest, unapplied)?;
+        self.metadata
+            .apply(dest, unapplied)
+            .context("apply metadata.toml")
+    }
+
+    pub fn load(&self) -> Result<FileNode> {
+        let mut node = self.source.load()?;
+        self.metadata
+            .overlay(&mut node, false)
+            .context("apply metadata.toml")?;
+        Ok(node)
+    }
+}
+
+/// A test's root after it ran, with the ownership it's only tracking virtually. Kept around so
+/// that tests chained after it can start from it.
+#[derive(Debug)]
+pub struct FinishedRoot {
+    /// The root's directory, or the layers of a copy-on-write root from the top down
+    pub(crate) layers: Vec<PathBuf>,
+    pub(crate) virtual_metadata: MetadataManifest,
+    /// The test's work directory, which goes away with the root
+    pub(crate) temporary: PathBuf,
+}
+
+impl FinishedRoot {
+    pub fn materialize(&self, dest: &Path) -> Result<MetadataManifest> {
+        let mut unapplied = MetadataManifest::default();
+        let mut layers = self.layers.iter().rev();
+        copy_tree(
+            layers.next().expect("at least one layer"),
+            dest,
+            &mut unapplied,
+        )?;
+        for layer in layers {
+            copy_layer(layer, dest, &mut unapplied)?;
+        }
+        // The copy can't tell virtual ownership apart from the files' actual ownership
+        unapplied.extend(self.virtual_metadata.clone());
+        Ok(unapplied)
+    }
+
+    pub fn load(&self) -> Result<FileNode> {
+        let mut node = overlay::load_layers(&self.layers)?;
+        // The program may have removed paths whose ownership was being tracked
+        self.virtual_metadata
+            .overlay(&mut node, true)
+            .context("apply virtual ownership")?;
+        Ok(node)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        remove_existing(&self.temporary).with_context(|| format!("remove {:?}", self.temporary))
+    }
+}
+
+/// Materialize `layers` into `dest` from the bottom up, each one over the previous ones.
+pub fn materialize_layers(layers: &[&Root], dest: &Path) -> Result<MetadataManifest> {
+    let mut unapplied = MetadataManifest::default();
+    for layer in layers {
+        layer
+            .materialize(dest, &mut unapplied)
+            .with_context(|| format!("copy {:?}", layer.source))?;
+    }
+    Ok(unapplied)
+}
+
+/// Load `layers` stacked from the bottom up, the way `materialize_layers` would lay them out.
+pub fn load_layers(layers: &[&Root]) -> Result<FileNode> {
+    let mut layers = layers.iter();
+    let bottom = layers.next().expect("at least one layer");
+    let mut node = bottom
+        .load()
+        .with_context(|| format!("load {:?}", bottom.source))?;
+    for layer in layers {
+        node.merge(
+            layer
+                .load()
+                .with_context(|| format!("load {:?}", layer.source))?,
+            false,
+        );
+    }
+    Ok(node)
+}
+
+/// The name of an archive without its extension, or `None` if `path` isn't an archive.
+fn archive_name(path: &Path) -> Option<&str> {
+    let file_name = path.file_name()?.to_str()?;
+    ARCHIVE_EXTENSIONS
+        .iter()
+        .find_map(|extension| file_name.strip_suffix(&format!(".{}", extension)))
+}
+
+fn open_archive(path: &Path) -> Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(path).context("open archive")?);
+    let name = path.to_string_lossy();
+    let reader: Box<dyn Read> = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Box::new(flate2::read::GzDecoder::new(file))
+    } else if name.ends_with(".tar.zst") {
+        Box::new(ruzstd::StreamingDecoder::new(file).context("start zstd decoder")?)
+    } else {
+        Box::new(file)
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+fn extract_archive(path: &Path, dest: &Path, unapplied: &mut MetadataManifest) -> Result<()> {
+    std::fs::create_dir_all(dest).context("create root directory")?;
+
+    // Directory modes are applied last, so that read-only directories can still be filled
+    let mut directory_modes = Vec::new();
+
+    let mut archive = open_archive(path)?;
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    for entry in archive.entries().context("read archive")? {
+        let mut entry = entry.context("read archive entry")?;
+        let entry_path = normalize_path(&entry.path().context("get entry path")?)?;
+        let full_path = dest.join(&entry_path);
+        let header = entry.header();
+        let kind = header.entry_type();
+        let mode = header.mode().context("get entry mode")? & 0o7777;
+        let uid = header.uid().context("get entry uid")? as u32;
+        let gid = header.gid().context("get entry gid")? as u32;
+
+        if kind.is_dir() {
+            std::fs::create_dir_all(&full_path)
+                .with_context(|| format!("create directory {:?}", entry_path))?;
+            directory_modes.push((entry_path.clone(), mode));
+        } else if let Some(special) = special_kind(kind) {
+            let device = libc::makedev(
+                header.device_major().ok().flatten().unwrap_or(0),
+                header.device_minor().ok().flatten().unwrap_or(0),
+            );
+            if full_path.symlink_metadata().is_ok() {
+                std::fs::remove_file(&full_path)
+                    .with_context(|| format!("replace {:?}", entry_path))?;
+            }
+            mknod(&full_path, special, mode, device)
+                .with_context(|| format!("create {} {:?}", special.name(), entry_path))?;
+        } else if kind.is_file()
+            || kind.is_contiguous()
+            || kind.is_gnu_sparse()
+            || kind.is_symlink()
+            || kind.is_hard_link()
+        {
+            entry
+                .unpack_in(dest)
+                .with_context(|| format!("unpack {:?}", entry_path))?;
+        } else {
+            debug!("Skipping archive entry {:?} of type {:?}", entry_path, kind);
+            continue;
+        }
+
+        unapplied.lchown_or_record(dest, &entry_path, Some(uid), Some(gid))?;
+    }
+
+    for (path, mode) in directory_modes.into_iter().rev() {
+        std::fs::set_permissions(dest.join(&path), std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("change mode of {:?}", path))?;
+    }
+
+    Ok(())
+}
+
+fn load_archive(path: &Path) -> Result<FileNode> {
+    let mut root = FileNode::Directory {
+        children: BTreeMap::new(),
+        permissions: Permissions::for_new(libc::S_IFDIR),
+    };
+
+    let mut archive = open_archive(path)?;
+    for entry in archive.entries().context("read archive")? {
+        let mut entry = entry.context("read archive entry")?;
+        let entry_path = normalize_path(&entry.path().context("get entry path")?)?;
+        let header = entry.header();
+        let kind = header.entry_type();
+        let permissions = |file_type: u32| -> Result<Permissions> {
+            Ok(Permissions {
+                mode: file_type | (header.mode().context("get entry mode")? & 0o7777),
+                uid: header.uid().context("get entry uid")? as u32,
+                gid: header.gid().context("get entry gid")? as u32,
+            })
+        };
+
+        let node = if kind.is_dir() {
+            FileNode::Directory {
+                children: BTreeMap::new(),
+                permissions: permissions(libc::S_IFDIR)?,
+            }
+        } else if kind.is_symlink() {
+            FileNode::SymbolicLink {
+                target: entry
+                    .link_name()
+                    .context("get symbolic link target")?
+                    .context("symbolic link without target")?
+                    .into_owned(),
+                permissions: Permissions {
+                    mode: libc::S_IFLNK | 0o777,
+                    ..permissions(0)?
+                },
+            }
+        } else if kind.is_hard_link() {
+            let target = entry
+                .link_name()
+                .context("get hard link target")?
+                .context("hard link without target")?;
+            let target = normalize_path(&target)?;
+            root.find_mut(&target)
+                .with_context(|| format!("find hard link target {:?}", target))?
+                .clone()
+        } else if let Some(special) = special_kind(kind) {
+            FileNode::Special {
+                kind: special,
+                device: libc::makedev(
+                    header.device_major().ok().flatten().unwrap_or(0),
+                    header.device_minor().ok().flatten().unwrap_or(0),
+                ),
+                permissions: permissions(special.file_type_bits())?,
+            }
+        } else if kind.is_file() || kind.is_contiguous() || kind.is_gnu_sparse() {
+            let permissions = permissions(libc::S_IFREG)?;
+            let mut contents = Vec::new();
+            entry
+                .read_to_end(&mut contents)
+                .with_context(|| format!("read contents of {:?}", entry_path))?;
+            FileNode::File {
+                contents,
+                permissions,
+            }
+        } else {
+            continue;
+        };
+
+        root.insert(&entry_path, node)
+            .with_context(|| format!("add {:?} to tree", entry_path))?;
+    }
+
+    Ok(root)
+}
+
+fn special_kind(kind: tar::EntryType) -> Option<SpecialKind> {
+    if kind.is_character_special() {
+        Some(SpecialKind::CharacterDevice)
+    } else if kind.is_block_special() {
+        Some(SpecialKind::BlockDevice)
+    } else if kind.is_fifo() {
+        Some(SpecialKind::Fifo)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn mknod(path: &Path, kind: SpecialKind, mode: u32, device: u64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).context("convert path to C string")?;
+    // SAFETY: c_path is a valid NUL-terminated string
+    if unsafe { libc::mknod(c_path.as_ptr(), kind.file_type_bits() | mode, device) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // mknod is subject to the umask, the archive's mode isn't
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).context("change mode")?;
+    Ok(())
+}
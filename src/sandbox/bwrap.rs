@@ -0,0 +1,41 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+
+use super::{host_program, Invocation, Sandbox};
+
+/// bubblewrap, with the root bind-mounted as `/` and the current user mapped to root.
+/// Nothing else is mounted, so that the root isn't changed by mount points.
+#[derive(Debug)]
+pub struct Bwrap;
+
+impl Sandbox for Bwrap {
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command> {
+        let mut command = Command::new(host_program("bwrap")?);
+        command
+            .arg("--bind")
+            .arg(root)
+            .arg("/")
+            .arg("--unshare-user")
+            .arg("--uid")
+            .arg("0")
+            .arg("--gid")
+            .arg("0")
+            .arg("--chdir")
+            .arg(invocation.cd);
+        if invocation.clear_environment {
+            command.arg("--clearenv");
+        }
+        // Set inside the sandbox so the environment doesn't affect bwrap itself
+        for (key, value) in invocation.environment {
+            command.arg("--setenv").arg(key).arg(value);
+        }
+        command.arg(invocation.program()).args(invocation.args());
+        Ok(command)
+    }
+
+    fn maps_current_user_to_root(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,45 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use super::{check, Invocation, Sandbox};
+
+/// A real `chroot`, which needs to run as root.
+#[derive(Debug)]
+pub struct Chroot;
+
+impl Chroot {
+    pub fn new() -> Result<Chroot> {
+        // SAFETY: geteuid is always successful
+        anyhow::ensure!(
+            unsafe { libc::geteuid() } == 0,
+            "the chroot backend needs to run as root"
+        );
+        Ok(Chroot)
+    }
+}
+
+impl Sandbox for Chroot {
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command> {
+        let root = CString::new(root.as_os_str().as_bytes()).context("convert root to C string")?;
+        let cd =
+            CString::new(invocation.cd.as_os_str().as_bytes()).context("convert cd to C string")?;
+
+        let mut command = Command::new(invocation.program());
+        command.args(invocation.args());
+        invocation.set_environment(&mut command);
+        // SAFETY: chroot and chdir are system calls, and their arguments are prepared before
+        // forking
+        unsafe {
+            command.pre_exec(move || {
+                check(libc::chroot(root.as_ptr()))?;
+                check(libc::chdir(cd.as_ptr()))
+            });
+        }
+        Ok(command)
+    }
+}
@@ -0,0 +1,31 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+
+use super::{host_program, Invocation, Sandbox};
+
+/// `fakechroot chroot`, which works without privileges by intercepting library calls.
+/// It doesn't work for statically linked programs.
+#[derive(Debug)]
+pub struct Fakechroot;
+
+impl Sandbox for Fakechroot {
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command> {
+        let mut command = Command::new(host_program("fakechroot")?);
+        command.arg(host_program("chroot")?).arg(root);
+        if invocation.cd != Path::new("/") {
+            // chroot always changes to the new root directory, so the root needs a shell to
+            // change to another one
+            command
+                .arg("/bin/sh")
+                .arg("-c")
+                .arg(r#"cd "$1" && shift && exec "$@""#)
+                .arg("sh")
+                .arg(invocation.cd);
+        }
+        command.arg(invocation.program()).args(invocation.args());
+        invocation.set_environment(&mut command);
+        Ok(command)
+    }
+}
@@ -0,0 +1,136 @@
+mod bwrap;
+mod chroot;
+mod fakechroot;
+mod native;
+mod unshare;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::overlay::Overlay;
+
+/// A way of running a command with a test's `root/` as its root directory.
+pub trait Sandbox: std::fmt::Debug {
+    /// Build the command that runs `invocation` inside `root`.
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command>;
+
+    /// Whether the command sees files owned by the current user as owned by root. Roots are then
+    /// compared the way the command sees them.
+    fn maps_current_user_to_root(&self) -> bool {
+        false
+    }
+
+    /// Whether the sandbox mounts the invocation's overlay on the root itself. Otherwise it's
+    /// mounted on the host before running the command.
+    fn mounts_overlay(&self) -> bool {
+        false
+    }
+}
+
+/// What to run inside the sandbox.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    /// The working directory inside the root
+    pub cd: &'a Path,
+    /// The program, looked up in the root's `PATH`, and its arguments
+    pub argv: &'a [String],
+    pub environment: &'a BTreeMap<String, String>,
+    /// Start from `environment` alone, instead of adding it to the host's environment
+    pub clear_environment: bool,
+    /// The copy-on-write layers to mount on the root, if any
+    pub overlay: Option<&'a Overlay>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Fakechroot,
+    Chroot,
+    Unshare,
+    Bwrap,
+    Native,
+}
+
+impl Backend {
+    pub fn sandbox(self) -> Result<Box<dyn Sandbox>> {
+        Ok(match self {
+            Backend::Fakechroot => Box::new(fakechroot::Fakechroot),
+            Backend::Chroot => Box::new(chroot::Chroot::new()?),
+            Backend::Unshare => Box::new(unshare::Unshare),
+            Backend::Bwrap => Box::new(bwrap::Bwrap),
+            Backend::Native => Box::new(native::Native),
+        })
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Backend::Fakechroot => "fakechroot",
+            Backend::Chroot => "chroot",
+            Backend::Unshare => "unshare",
+            Backend::Bwrap => "bwrap",
+            Backend::Native => "native",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fakechroot" => Ok(Backend::Fakechroot),
+            "chroot" => Ok(Backend::Chroot),
+            "unshare" => Ok(Backend::Unshare),
+            "bwrap" => Ok(Backend::Bwrap),
+            "native" => Ok(Backend::Native),
+            _ => anyhow::bail!(
+                "unknown backend {:?}, expected fakechroot, chroot, unshare, bwrap or native",
+                s
+            ),
+        }
+    }
+}
+
+impl Invocation<'_> {
+    pub fn program(&self) -> &str {
+        &self.argv[0]
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.argv[1..]
+    }
+
+    /// Give `command` the invocation's environment.
+    fn set_environment(&self, command: &mut Command) {
+        if self.clear_environment {
+            command.env_clear();
+        }
+        command.envs(self.environment);
+    }
+}
+
+/// Find `program` in the host's `PATH`. Sandbox tools are looked up this way so they're still
+/// found once the command's environment is replaced with the test's.
+pub fn host_program(program: &str) -> Result<PathBuf> {
+    let path = std::env::var_os("PATH").context("get PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+        .with_context(|| format!("find {} in PATH", program))
+}
+
+pub fn check(ret: libc::c_int) -> std::io::Result<()> {
+    if ret == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
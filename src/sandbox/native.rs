@@ -0,0 +1,123 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+use super::{check, Invocation, Sandbox};
+
+/// Runs the command directly, in new user and mount namespaces set up by Roottest itself: the
+/// current user is mapped to root, and the root directory is pivoted to the test's root.
+/// Needs nothing but a kernel with unprivileged user namespaces enabled.
+#[derive(Debug)]
+pub struct Native;
+
+/// Everything the child needs after forking, prepared beforehand so it doesn't have to allocate.
+struct Setup {
+    root: CString,
+    cd: CString,
+    /// Options for mounting an overlay on the root, instead of bind-mounting it
+    overlay_options: Option<CString>,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl Sandbox for Native {
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command> {
+        let root = root.canonicalize().context("resolve root directory")?;
+        let setup = Setup {
+            root: CString::new(root.as_os_str().as_bytes()).context("convert root to C string")?,
+            cd: CString::new(invocation.cd.as_os_str().as_bytes())
+                .context("convert cd to C string")?,
+            overlay_options: match invocation.overlay {
+                // The current user can't set the trusted.* attributes overlayfs uses by default
+                Some(overlay) => Some(
+                    CString::new(format!("userxattr,{}", overlay.mount_options()?))
+                        .context("convert overlay options to C string")?,
+                ),
+                None => None,
+            },
+            // SAFETY: getuid and getgid are always successful
+            uid_map: format!("0 {} 1", unsafe { libc::getuid() }).into_bytes(),
+            gid_map: format!("0 {} 1", unsafe { libc::getgid() }).into_bytes(),
+        };
+
+        let mut command = Command::new(invocation.program());
+        command.args(invocation.args());
+        invocation.set_environment(&mut command);
+        // SAFETY: enter_root only makes system calls, using data prepared before forking
+        unsafe {
+            command.pre_exec(move || enter_root(&setup));
+        }
+        Ok(command)
+    }
+
+    fn maps_current_user_to_root(&self) -> bool {
+        true
+    }
+
+    fn mounts_overlay(&self) -> bool {
+        true
+    }
+}
+
+fn enter_root(setup: &Setup) -> io::Result<()> {
+    // SAFETY: all pointers passed are NUL-terminated strings that outlive the calls
+    unsafe {
+        check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+        // Writing gid_map requires giving up setgroups first
+        write_file(b"/proc/self/setgroups\0", b"deny")?;
+        write_file(b"/proc/self/uid_map\0", &setup.uid_map)?;
+        write_file(b"/proc/self/gid_map\0", &setup.gid_map)?;
+
+        // Don't let the mounts below propagate back to the host
+        check(libc::mount(
+            std::ptr::null(),
+            b"/\0".as_ptr().cast(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        ))?;
+        // pivot_root needs the new root to be a mount point
+        match &setup.overlay_options {
+            Some(options) => check(libc::mount(
+                b"overlay\0".as_ptr().cast(),
+                setup.root.as_ptr(),
+                b"overlay\0".as_ptr().cast(),
+                0,
+                options.as_ptr().cast(),
+            ))?,
+            None => check(libc::mount(
+                setup.root.as_ptr(),
+                setup.root.as_ptr(),
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+                std::ptr::null(),
+            ))?,
+        }
+        check(libc::chdir(setup.root.as_ptr()))?;
+        // Stack the old root on top of the new one, then detach it
+        check(
+            libc::syscall(libc::SYS_pivot_root, b".\0".as_ptr(), b".\0".as_ptr()) as libc::c_int,
+        )?;
+        check(libc::umount2(b".\0".as_ptr().cast(), libc::MNT_DETACH))?;
+        check(libc::chdir(setup.cd.as_ptr()))?;
+    }
+    Ok(())
+}
+
+/// # Safety
+/// `path` must be NUL-terminated.
+unsafe fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = libc::open(path.as_ptr().cast(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+    libc::close(fd);
+    if written != contents.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
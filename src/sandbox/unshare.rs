@@ -0,0 +1,33 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Result;
+
+use super::{host_program, Invocation, Sandbox};
+
+/// `unshare` from util-linux, in new user and mount namespaces with the current user mapped to
+/// root. Needs unprivileged user namespaces.
+#[derive(Debug)]
+pub struct Unshare;
+
+impl Sandbox for Unshare {
+    fn command(&self, root: &Path, invocation: &Invocation) -> Result<Command> {
+        let mut command = Command::new(host_program("unshare")?);
+        command
+            .arg("--user")
+            .arg("--map-root-user")
+            .arg("--mount")
+            .arg("--root")
+            .arg(root)
+            .arg("--wd")
+            .arg(invocation.cd)
+            .arg(invocation.program())
+            .args(invocation.args());
+        invocation.set_environment(&mut command);
+        Ok(command)
+    }
+
+    fn maps_current_user_to_root(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::tests::{RootTest, RunOptions};
+
+/// Load the tests that chained tests start from but that weren't given, from the suite of the
+/// tests that need them. Tests that were given are found by their directory, so each chained test
+/// refers to the test it starts from by that test's name.
+pub fn load_prerequisites(
+    tests: &mut Vec<RootTest>,
+    work_dir: &Path,
+    options: &RunOptions,
+) -> Result<()> {
+    let mut names_by_dir = BTreeMap::new();
+    for test in tests.iter() {
+        let dir = test
+            .dir
+            .canonicalize()
+            .with_context(|| format!("resolve directory of {}", test.name))?;
+        names_by_dir.insert(dir, test_name(&test.name).to_string());
+    }
+
+    let mut i = 0;
+    while i < tests.len() {
+        let prerequisite = match tests[i].prerequisite() {
+            Some(prerequisite) => prerequisite.to_string(),
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        let test_name = test_name(&prerequisite);
+        // Tests are named by their path in the suite, which is somewhere above this one
+        let dir = tests[i]
+            .dir
+            .ancestors()
+            .skip(1)
+            .map(|suite| suite.join(test_name))
+            .find(|dir| dir.join("Roottest.toml").is_file());
+        let dir = match dir {
+            Some(dir) => dir
+                .canonicalize()
+                .with_context(|| format!("resolve directory of {}", test_name))?,
+            // Given by its name from somewhere else
+            None if tests.iter().any(|test| test.name == prerequisite) => {
+                i += 1;
+                continue;
+            }
+            None => anyhow::bail!("find test {} that {} starts from", test_name, tests[i].name),
+        };
+
+        let name = match names_by_dir.get(&dir) {
+            Some(name) => name.clone(),
+            None => {
+                debug!("Loading prerequisite {:?} of {}", dir, tests[i].name);
+                // It belongs to the same suite, unless it was found above it
+                let suite = tests[i].suite.clone().filter(|suite| {
+                    suite
+                        .canonicalize()
+                        .is_ok_and(|suite| dir.starts_with(suite))
+                });
+                let cases =
+                    RootTest::from_dir(&dir, test_name, suite.as_deref(), work_dir, options)
+                        .with_context(|| {
+                            format!(
+                                "load test {} that {} starts from",
+                                prerequisite, tests[i].name
+                            )
+                        })?;
+                names_by_dir.insert(dir, test_name.to_string());
+                tests.extend(cases);
+                test_name.to_string()
+            }
+        };
+        let case = &prerequisite[test_name.len()..];
+        tests[i].set_prerequisite(format!("{}{}", name, case));
+        i += 1;
+    }
+    Ok(())
+}
+
+/// The name of the test that `name` is a case of, or `name` itself. A case of a matrix test is
+/// named after the test, followed by its values in brackets.
+fn test_name(name: &str) -> &str {
+    name.split('[').next().unwrap_or(name)
+}
+
+/// Order tests so that each one runs after the test it starts from, keeping the given order
+/// otherwise. Tests whose roots are needed by later tests are marked to keep them.
+pub fn order(tests: Vec<RootTest>) -> Result<Vec<RootTest>> {
+    let mut pending: BTreeMap<String, RootTest> = BTreeMap::new();
+    let mut names = Vec::with_capacity(tests.len());
+    for test in tests {
+        anyhow::ensure!(
+            !pending.contains_key(&test.name),
+            "two tests are named {}",
+            test.name
+        );
+        names.push(test.name.clone());
+        pending.insert(test.name.clone(), test);
+    }
+
+    let prerequisites: BTreeSet<String> = pending
+        .values()
+        .filter_map(|test| test.prerequisite().map(str::to_string))
+        .collect();
+    for name in &prerequisites {
+        pending
+            .get_mut(name)
+            .with_context(|| format!("find test {} that other tests start from", name))?
+            .keep_root = true;
+    }
+
+    let mut ordered = Vec::with_capacity(names.len());
+    for name in names {
+        add_with_prerequisites(&name, &mut pending, &mut ordered, &mut Vec::new())?;
+    }
+    Ok(ordered)
+}
+
+fn add_with_prerequisites(
+    name: &str,
+    pending: &mut BTreeMap<String, RootTest>,
+    ordered: &mut Vec<RootTest>,
+    chain: &mut Vec<String>,
+) -> Result<()> {
+    if chain.iter().any(|n| n == name) {
+        chain.push(name.to_string());
+        anyhow::bail!(
+            "tests start from each other in a cycle: {}",
+            chain.join(" -> ")
+        );
+    }
+
+    let test = match pending.remove(name) {
+        Some(test) => test,
+        // Already ordered
+        None => return Ok(()),
+    };
+
+    if let Some(prerequisite) = test.prerequisite() {
+        chain.push(name.to_string());
+        let prerequisite = prerequisite.to_string();
+        // Put the test back while its prerequisites are ordered, so a cycle leads back to it
+        pending.insert(name.to_string(), test);
+        add_with_prerequisites(&prerequisite, pending, ordered, chain)?;
+        chain.pop();
+        let test = pending.remove(name).expect("test put back above");
+        ordered.push(test);
+    } else {
+        ordered.push(test);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::sandbox::Backend;
+    use crate::tests::StartingRoot;
+
+    /// A test named `name`, which starts from `from_test` if it's given.
+    fn test(name: &str, from_test: Option<&str>) -> RootTest {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roottest/pwd_success");
+        let options = RunOptions {
+            cleanup: true,
+            include_ignored: false,
+            backend: Backend::default(),
+            overlay: false,
+            inject: BTreeMap::new(),
+            provision: Vec::new(),
+            environment: BTreeMap::new(),
+            hermetic: false,
+        };
+        let mut test = RootTest::from_dir(&dir, name, Some(&dir), &PathBuf::new(), &options)
+            .unwrap()
+            .remove(0);
+        if let Some(from_test) = from_test {
+            test.root_before = StartingRoot::FromTest(from_test.to_string());
+        }
+        test
+    }
+
+    fn names(tests: &[RootTest]) -> Vec<&str> {
+        tests.iter().map(|test| test.name.as_str()).collect()
+    }
+
+    #[test]
+    fn orders_tests_after_their_prerequisites() {
+        let tests = order(vec![
+            test("upgrade", Some("install")),
+            test("other", None),
+            test("install", Some("base")),
+            test("base", None),
+        ])
+        .unwrap();
+        assert_eq!(names(&tests), ["base", "install", "upgrade", "other"]);
+        let kept: Vec<_> = tests.iter().map(|test| test.keep_root).collect();
+        assert_eq!(kept, [true, true, false, false]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let error = order(vec![
+            test("a", Some("b")),
+            test("b", Some("c")),
+            test("c", Some("a")),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tests start from each other in a cycle: a -> b -> c -> a"
+        );
+        assert!(order(vec![test("self", Some("self"))]).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_duplicate_tests() {
+        assert!(order(vec![test("a", Some("missing"))]).is_err());
+        assert!(order(vec![test("a", None), test("a", None)]).is_err());
+    }
+
+    #[test]
+    fn splits_case_names() {
+        assert_eq!(test_name("install[LANG=C]"), "install");
+        assert_eq!(test_name("group/install"), "group/install");
+    }
+}
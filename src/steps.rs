@@ -0,0 +1,270 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::interaction::{ActionParams, Interaction};
+use crate::matrix::Case;
+use crate::roots::Root;
+
+/// A `[[step]]` table in `Roottest.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepParams {
+    pub(crate) name: Option<String>,
+    pub(crate) run: Run,
+    pub(crate) expected_status: Option<i32>,
+    pub(crate) stdin: Option<String>,
+    pub(crate) expected_stdout: Option<String>,
+    pub(crate) expected_stderr: Option<String>,
+    pub(crate) expected_screen: Option<String>,
+    pub(crate) expected_output: Option<String>,
+    pub(crate) interaction: Option<Vec<ActionParams>>,
+}
+
+/// A command to run in the root:
+///
+/// ```toml
+/// run = ["/program", "--flag", "arg with spaces"]
+/// ```
+///
+/// A string is a script for the test's `shell` instead.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Run {
+    Argv(Vec<String>),
+    Shell(String),
+}
+
+impl Run {
+    /// The program to run and its arguments, running scripts with `shell`.
+    pub fn argv(&self, shell: &str) -> Vec<String> {
+        match self {
+            Run::Argv(argv) => argv.clone(),
+            Run::Shell(script) => shell_argv(shell, script),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            !matches!(self, Run::Argv(argv) if argv.is_empty()),
+            "run can't be an empty array"
+        );
+        Ok(())
+    }
+}
+
+/// A `setup` or `teardown` command, run inside the root unless it's run on the host:
+///
+/// ```toml
+/// setup = { run = "truncate -s 2G $ROOTTEST_ROOT/var/big.img", on_host = true }
+/// ```
+///
+/// On the host, it runs in the test's directory, with the root's path in `ROOTTEST_ROOT`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Hook {
+    InRoot(String),
+    Table {
+        run: String,
+        #[serde(default)]
+        on_host: bool,
+    },
+}
+
+impl Hook {
+    pub fn run(&self) -> &str {
+        match self {
+            Hook::InRoot(run) | Hook::Table { run, .. } => run,
+        }
+    }
+
+    pub fn on_host(&self) -> bool {
+        matches!(self, Hook::Table { on_host: true, .. })
+    }
+}
+
+/// Run `script` with `shell`, like `sh -c`.
+pub fn shell_argv(shell: &str, script: &str) -> Vec<String> {
+    vec![shell.to_string(), "-c".to_string(), script.to_string()]
+}
+
+/// One command of a test, with what it should do. Tests that only set `run` have a single,
+/// unnamed step.
+#[derive(Debug)]
+pub struct Step {
+    pub(crate) name: Option<String>,
+    pub(crate) run: Run,
+    pub(crate) stdin: Vec<u8>,
+    /// Drives stdin instead of `stdin`
+    pub(crate) interaction: Option<Interaction>,
+    /// Not checked if only the combined output is
+    pub(crate) expected_stdout: Option<Vec<u8>>,
+    pub(crate) expected_stderr: Option<Vec<u8>>,
+    /// Both streams marked line by line, if that's checked
+    pub(crate) expected_output: Option<Vec<u8>>,
+    /// What the terminal should show at the end, if that's checked
+    pub(crate) expected_screen: Option<Vec<u8>>,
+    pub(crate) expected_status: i32,
+    /// What the root should look like after this step, if that's checked
+    pub(crate) root_after: Option<Root>,
+}
+
+impl Step {
+    /// The only step of a test, with its input and expected output in the test's directory.
+    pub fn single(
+        dir: &Path,
+        case: &Case,
+        run: &Run,
+        interaction: &Option<Vec<ActionParams>>,
+        expected_status: i32,
+    ) -> Result<Step> {
+        run.validate()?;
+        let stdin = std::fs::read(case.select(&dir.join("input.stdin"))?).context("load stdin")?;
+        trace!("Stdin: {:#?}", stdin);
+        let interaction = load_interaction(interaction, &stdin)?;
+        let expected_output =
+            inline_or_optional_file(&None, &case.select(&dir.join("expected.output"))?)?;
+        trace!("Output: {:#?}", expected_output);
+        // Both are required, unless the combined output is checked instead
+        let load = |name: &str| -> Result<Option<Vec<u8>>> {
+            let path = case.select(&dir.join(name))?;
+            if expected_output.is_some() && !path.exists() {
+                return Ok(None);
+            }
+            Ok(Some(
+                std::fs::read(&path).with_context(|| format!("load {}", name))?,
+            ))
+        };
+        let expected_stdout = load("expected.stdout")?;
+        trace!("Stdout: {:#?}", expected_stdout);
+        let expected_stderr = load("expected.stderr")?;
+        trace!("Stderr: {:#?}", expected_stderr);
+        let expected_screen =
+            inline_or_optional_file(&None, &case.select(&dir.join("expected.screen"))?)?;
+        trace!("Screen: {:#?}", expected_screen);
+
+        Ok(Step {
+            name: None,
+            run: run.clone(),
+            stdin,
+            interaction,
+            expected_stdout,
+            expected_stderr,
+            expected_output,
+            expected_screen,
+            expected_status,
+            root_after: None,
+        })
+    }
+
+    /// Load the `[[step]]` tables of the test in `dir`. Steps are named by their position unless
+    /// they have a name. Their input and expected output are given inline, or as files in
+    /// `steps/<name>/` named like the test's, next to an optional `root_after`.
+    pub fn load_all(
+        dir: &Path,
+        case: &Case,
+        params: &[StepParams],
+        default_status: i32,
+    ) -> Result<Vec<Step>> {
+        let mut names = BTreeSet::new();
+        params
+            .iter()
+            .enumerate()
+            .map(|(i, params)| {
+                let name = params.name.clone().unwrap_or_else(|| (i + 1).to_string());
+                anyhow::ensure!(
+                    matches!(
+                        Path::new(&name).components().collect::<Vec<_>>()[..],
+                        [Component::Normal(_)]
+                    ),
+                    "invalid step name {:?}",
+                    name
+                );
+                anyhow::ensure!(names.insert(name.clone()), "two steps are named {}", name);
+                Step::load(
+                    &dir.join("steps").join(&name),
+                    case,
+                    name.clone(),
+                    params,
+                    default_status,
+                )
+                .with_context(|| format!("load step {}", name))
+            })
+            .collect()
+    }
+
+    fn load(
+        dir: &Path,
+        case: &Case,
+        name: String,
+        params: &StepParams,
+        default_status: i32,
+    ) -> Result<Step> {
+        params.run.validate()?;
+        let file = |name| case.select(&dir.join(name));
+        let stdin = inline_or_file(&params.stdin, &file("input.stdin")?)?;
+        let expected_output =
+            inline_or_optional_file(&params.expected_output, &file("expected.output")?)?;
+        // Empty unless given, or unchecked if the combined output is checked instead
+        let expected_stream = |inline, path| -> Result<Option<Vec<u8>>> {
+            match inline_or_optional_file(inline, path)? {
+                None if expected_output.is_none() => Ok(Some(Vec::new())),
+                expected => Ok(expected),
+            }
+        };
+        let step = Step {
+            interaction: load_interaction(&params.interaction, &stdin)?,
+            stdin,
+            expected_stdout: expected_stream(&params.expected_stdout, &file("expected.stdout")?)?,
+            expected_stderr: expected_stream(&params.expected_stderr, &file("expected.stderr")?)?,
+            expected_output,
+            expected_screen: inline_or_optional_file(
+                &params.expected_screen,
+                &file("expected.screen")?,
+            )?,
+            expected_status: params.expected_status.unwrap_or(default_status),
+            root_after: Root::find_optional(dir, &case.select_root(dir, "root_after")?)?,
+            run: params.run.clone(),
+            name: Some(name),
+        };
+        trace!("Step: {:#?}", step);
+        Ok(step)
+    }
+}
+
+fn load_interaction(
+    params: &Option<Vec<ActionParams>>,
+    stdin: &[u8],
+) -> Result<Option<Interaction>> {
+    match params {
+        Some(_) if !stdin.is_empty() => {
+            anyhow::bail!("stdin can't be combined with interaction")
+        }
+        Some(params) => Ok(Some(Interaction::new(params)?)),
+        None => Ok(None),
+    }
+}
+
+/// Contents given inline in `Roottest.toml` or in the file at `path`, empty if neither exists.
+fn inline_or_file(inline: &Option<String>, path: &Path) -> Result<Vec<u8>> {
+    Ok(inline_or_optional_file(inline, path)?.unwrap_or_default())
+}
+
+/// Contents given inline in `Roottest.toml` or in the file at `path`, if either exists.
+fn inline_or_optional_file(inline: &Option<String>, path: &Path) -> Result<Option<Vec<u8>>> {
+    match inline {
+        Some(_) if path.exists() => {
+            anyhow::bail!(
+                "{:?} can't be combined with the same value given inline",
+                path
+            )
+        }
+        Some(inline) => Ok(Some(inline.clone().into_bytes())),
+        None if path.exists() => std::fs::read(path)
+            .map(Some)
+            .with_context(|| format!("read {:?}", path)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::process::Child;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+
+use crate::sandbox::check;
+
+/// The end-of-file character of a terminal in its default settings, `^D`
+const VEOF: u8 = 0x04;
+
+/// The input and output of a running command, read and written together so that neither side
+/// can block the other.
+#[derive(Debug)]
+pub struct Streams {
+    /// `None` once it's closed
+    input: Option<File>,
+    pending_input: Vec<u8>,
+    close_input: bool,
+    /// Input goes to a terminal, which is never closed, but sent end-of-file
+    terminal: bool,
+    /// The last byte typed into the terminal
+    last_input: Option<u8>,
+    /// The streams that haven't ended yet
+    outputs: Vec<(File, Stream)>,
+    captured: Captured,
+    /// The unfinished last line of stdout and stderr, when recording the combined output
+    unfinished_lines: Option<[Vec<u8>; 2]>,
+    /// The output that no `wait_for` consumed yet, from both streams
+    unread: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// What a command wrote.
+#[derive(Debug, Default)]
+pub struct Captured {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Both streams line by line, in the order the lines were read, if that's recorded
+    pub combined: Option<Vec<u8>>,
+}
+
+impl Stream {
+    fn name(self) -> &'static [u8] {
+        match self {
+            Stream::Stdout => b"stdout",
+            Stream::Stderr => b"stderr",
+        }
+    }
+
+    fn marker(self) -> &'static [u8] {
+        match self {
+            Stream::Stdout => b"stdout: ",
+            Stream::Stderr => b"stderr: ",
+        }
+    }
+}
+
+/// Why `wait_for` returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Wait {
+    Found,
+    TimedOut,
+    /// The command closed its output
+    Ended,
+}
+
+impl Streams {
+    /// The piped stdin, stdout and stderr of `process`.
+    pub fn from_pipes(process: &mut Child) -> Result<Streams> {
+        let input = File::from(OwnedFd::from(process.stdin.take().expect("piped stdin")));
+        let stdout = File::from(OwnedFd::from(process.stdout.take().expect("piped stdout")));
+        let stderr = File::from(OwnedFd::from(process.stderr.take().expect("piped stderr")));
+        Streams::new(
+            input,
+            vec![(stdout, Stream::Stdout), (stderr, Stream::Stderr)],
+            false,
+        )
+    }
+
+    /// The master side of a pseudo-terminal, whose output all counts as stdout.
+    pub fn from_terminal(master: File) -> Result<Streams> {
+        let input = master.try_clone().context("duplicate pseudo-terminal")?;
+        Streams::new(input, vec![(master, Stream::Stdout)], true)
+    }
+
+    fn new(input: File, outputs: Vec<(File, Stream)>, terminal: bool) -> Result<Streams> {
+        for file in std::iter::once(&input).chain(outputs.iter().map(|(file, _)| file)) {
+            // SAFETY: fcntl only changes flags of a descriptor we own
+            check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) })
+                .context("make command's streams non-blocking")?;
+        }
+        Ok(Streams {
+            input: Some(input),
+            pending_input: Vec::new(),
+            close_input: false,
+            terminal,
+            last_input: None,
+            outputs,
+            captured: Captured::default(),
+            unfinished_lines: None,
+            unread: Vec::new(),
+        })
+    }
+
+    /// Also record both streams together, each line marked with the stream it's from:
+    ///
+    /// ```text
+    /// stdout: Installing
+    /// stderr: warning: no configuration
+    /// stdout: Done
+    /// ```
+    pub fn record_combined(&mut self) {
+        self.captured.combined = Some(Vec::new());
+        self.unfinished_lines = Some([Vec::new(), Vec::new()]);
+    }
+
+    /// Write `input` to the command, after what was sent before.
+    pub fn send(&mut self, input: &[u8]) {
+        self.pending_input.extend_from_slice(input);
+    }
+
+    /// Close the command's stdin once everything sent was written. A terminal gets end-of-file
+    /// instead, after ending the line typed last.
+    pub fn close_input(&mut self) {
+        if self.terminal && !self.close_input {
+            let last = self.pending_input.last().copied().or(self.last_input);
+            if !matches!(last, None | Some(b'\n') | Some(VEOF)) {
+                // The first end-of-file only ends the unfinished line
+                self.pending_input.push(VEOF);
+            }
+            self.pending_input.push(VEOF);
+        }
+        self.close_input = true;
+    }
+
+    /// Read and write until `find` finds something in the output that wasn't read yet, or
+    /// `deadline` passes, or the output ends. `find` returns how much of the output it read.
+    pub fn wait_for(
+        &mut self,
+        deadline: Option<Instant>,
+        mut find: impl FnMut(&[u8]) -> Option<usize>,
+    ) -> Result<Wait> {
+        loop {
+            if let Some(read) = find(&self.unread) {
+                self.unread.drain(..read);
+                return Ok(Wait::Found);
+            }
+            if self.outputs.is_empty() {
+                return Ok(Wait::Ended);
+            }
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => left.as_millis().min(i32::MAX as u128) as i32,
+                    None => return Ok(Wait::TimedOut),
+                },
+                None => -1,
+            };
+            self.poll(timeout)?;
+        }
+    }
+
+    /// The output that wasn't read by `wait_for` yet.
+    pub fn unread(&self) -> &[u8] {
+        &self.unread
+    }
+
+    /// Read and write until the output ends, and return what the command wrote.
+    pub fn finish(mut self) -> Result<Captured> {
+        self.close_input();
+        while !self.outputs.is_empty() {
+            self.poll(-1)?;
+        }
+        Ok(self.into_captured())
+    }
+
+    /// Return what the command wrote after reading what's there already, for a command that was
+    /// stopped, whose output may never end if it started other programs.
+    pub fn abandon(mut self) -> Result<Captured> {
+        while !self.outputs.is_empty() && self.poll(0)? {}
+        Ok(self.into_captured())
+    }
+
+    fn into_captured(mut self) -> Captured {
+        if let (Some(combined), Some(unfinished_lines)) =
+            (&mut self.captured.combined, &self.unfinished_lines)
+        {
+            for (stream, line) in [Stream::Stdout, Stream::Stderr]
+                .iter()
+                .zip(unfinished_lines)
+            {
+                if !line.is_empty() {
+                    combined.extend_from_slice(stream.marker());
+                    combined.extend_from_slice(line);
+                    combined.extend_from_slice(b"\n\\ No newline at end of ");
+                    combined.extend_from_slice(stream.name());
+                    combined.push(b'\n');
+                }
+            }
+        }
+        self.captured
+    }
+
+    fn record(&mut self, stream: Stream, data: &[u8]) {
+        match stream {
+            Stream::Stdout => self.captured.stdout.extend_from_slice(data),
+            Stream::Stderr => self.captured.stderr.extend_from_slice(data),
+        }
+        self.unread.extend_from_slice(data);
+
+        if let (Some(combined), Some(unfinished_lines)) =
+            (&mut self.captured.combined, &mut self.unfinished_lines)
+        {
+            let unfinished = &mut unfinished_lines[stream as usize];
+            for piece in data.split_inclusive(|&b| b == b'\n') {
+                unfinished.extend_from_slice(piece);
+                if piece.ends_with(b"\n") {
+                    combined.extend_from_slice(stream.marker());
+                    combined.append(unfinished);
+                }
+            }
+        }
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it's -1, then read and write what's
+    /// ready. Returns whether anything was.
+    fn poll(&mut self, timeout: i32) -> Result<bool> {
+        if self.close_input && self.pending_input.is_empty() && !self.terminal {
+            self.input = None;
+        }
+        let writing = self.input.is_some() && !self.pending_input.is_empty();
+        let mut poll_fds: Vec<libc::pollfd> = self
+            .outputs
+            .iter()
+            .map(|(file, _)| libc::pollfd {
+                fd: file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        if let (true, Some(input)) = (writing, &self.input) {
+            poll_fds.push(libc::pollfd {
+                fd: input.as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            });
+        }
+
+        // SAFETY: poll_fds is a valid array of pollfds for the duration of the call
+        let ready = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as _, timeout) };
+        if ready == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(true);
+            }
+            return Err(e).context("wait for command's streams");
+        }
+        if ready == 0 {
+            return Ok(false);
+        }
+
+        if writing && poll_fds.last().expect("input").revents != 0 {
+            self.write_input();
+        }
+        let mut ended = Vec::new();
+        let mut buffer = [0; 4096];
+        for (i, poll_fd) in poll_fds.iter().enumerate().take(self.outputs.len()) {
+            if poll_fd.revents == 0 {
+                continue;
+            }
+            let (file, stream) = &mut self.outputs[i];
+            match file.read(&mut buffer) {
+                Ok(0) => ended.push(i),
+                Ok(read) => {
+                    let stream = *stream;
+                    self.record(stream, &buffer[..read]);
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                    ) => {}
+                // What Linux reports on a terminal once the other side is closed
+                Err(e) if self.terminal && e.raw_os_error() == Some(libc::EIO) => ended.push(i),
+                Err(e) => return Err(e).context("read command's output"),
+            }
+        }
+        for i in ended.into_iter().rev() {
+            self.outputs.remove(i);
+        }
+        Ok(true)
+    }
+
+    fn write_input(&mut self) {
+        let input = self.input.as_mut().expect("open stdin");
+        match input.write(&self.pending_input) {
+            Ok(written) => {
+                self.last_input = self.pending_input[..written].last().copied();
+                self.pending_input.drain(..written);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                debug!("Program did not consume all of stdin: {}", e);
+                self.pending_input.clear();
+                self.input = None;
+            }
+        }
+    }
+}
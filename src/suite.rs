@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+const FILE_NAME: &str = "Roottest.suite.toml";
+
+/// A `Roottest.suite.toml`, with defaults for the tests in its directory tree:
+///
+/// ```toml
+/// [defaults]
+/// cd = "/home/user"
+/// expected_status = 0
+/// backend = "native"
+///
+/// [defaults.environment]
+/// PATH = "/bin:/mybin"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SuiteParams {
+    /// Files in the directories above this one aren't used
+    #[serde(default)]
+    root: bool,
+    #[serde(default)]
+    defaults: toml::value::Table,
+}
+
+/// What the tests of a suite get unless they set it themselves.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    /// Defaults for `Roottest.toml`
+    params: toml::value::Table,
+    /// Defaults for `environment.toml`
+    environment: BTreeMap<String, String>,
+}
+
+impl Defaults {
+    /// The defaults for the test in `dir`, from every `Roottest.suite.toml` in the directories
+    /// above it, up to `suite` if the test was found in one, or to one that sets `root = true`.
+    /// Nearer ones override those further up.
+    pub fn find(dir: &Path, suite: Option<&Path>) -> Result<Defaults> {
+        let dir = dir.canonicalize().context("resolve test's directory")?;
+        let suite = match suite {
+            Some(suite) => Some(suite.canonicalize().context("resolve suite's directory")?),
+            None => None,
+        };
+        let mut files = Vec::new();
+        for suite_dir in dir.ancestors().skip(1) {
+            if suite
+                .as_ref()
+                .is_some_and(|suite| !suite_dir.starts_with(suite))
+            {
+                break;
+            }
+            let path = suite_dir.join(FILE_NAME);
+            if path.is_file() {
+                debug!("Loading suite defaults from {:?}", path);
+                let (defaults, root) =
+                    Defaults::load(&path).with_context(|| format!("load {:?}", path))?;
+                files.push(defaults);
+                if root {
+                    break;
+                }
+            }
+        }
+
+        let mut defaults = Defaults::default();
+        for nearer in files.into_iter().rev() {
+            defaults.extend(nearer);
+        }
+        Ok(defaults)
+    }
+
+    /// The defaults in the file at `path`, and whether it's the suite's root.
+    fn load(path: &Path) -> Result<(Defaults, bool)> {
+        let suite: SuiteParams =
+            toml::from_str(&read_to_string(path).context("read file")?).context("parse file")?;
+        let mut params = suite.defaults;
+        let environment = match params.remove("environment") {
+            Some(environment) => environment
+                .try_into()
+                .context("parse defaults.environment")?,
+            None => BTreeMap::new(),
+        };
+        let defaults = Defaults {
+            params,
+            environment,
+        };
+        Ok((defaults, suite.root))
+    }
+
+    /// Override these defaults with `nearer` ones.
+    fn extend(&mut self, nearer: Defaults) {
+        let mut params = nearer.params;
+        merge(&self.params, &mut params);
+        self.params = params;
+        self.environment.extend(nearer.environment);
+    }
+
+    /// Add the defaults to a test's `Roottest.toml`, where it doesn't set them. Tables are
+    /// merged key by key.
+    pub fn apply(&self, document: &mut toml::Value) {
+        if let Some(table) = document.as_table_mut() {
+            merge(&self.params, table);
+        }
+    }
+
+    pub fn environment(&self) -> &BTreeMap<String, String> {
+        &self.environment
+    }
+}
+
+fn merge(defaults: &toml::value::Table, table: &mut toml::value::Table) {
+    for (key, default) in defaults {
+        match (table.get_mut(key), default) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(defaults)) => {
+                merge(defaults, table)
+            }
+            (Some(_), _) => {}
+            (None, _) => {
+                table.insert(key.clone(), default.clone());
+            }
+        }
+    }
+}
@@ -1,83 +1,430 @@
 use std::collections::BTreeMap;
 use std::fs::read_to_string;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
 
-use crate::results::RootTestResult;
+use crate::changes::RootChanges;
+use crate::copy::remove_existing;
+use crate::faketime::{self, FakeTimeParams};
+use crate::hermetic;
+use crate::inject::{InjectParams, Injection};
+use crate::interaction::{ActionParams, Interaction};
+use crate::limits::{self, Limits};
+use crate::matrix::Case;
+use crate::metadata::MetadataManifest;
+use crate::mock::{self, MockParams};
+use crate::overlay::{FuseMount, Overlay};
+use crate::results::{FileNode, RootTestResult, StepResult};
+use crate::roots::{load_layers, materialize_layers, FinishedRoot, Root, RootSource};
+use crate::sandbox::{Backend, Invocation, Sandbox};
+use crate::steps::{shell_argv, Hook, Run, Step, StepParams};
+use crate::streams::Streams;
+use crate::suite::Defaults;
+use crate::tty::{Terminal, TtyParams};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
 
+/// Marks the directories Roottest made for tests' output, the only ones it removes before a run
+const WORK_DIR_MARKER: &str = ".roottest";
+
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RootTestParams {
     pub(crate) cd: PathBuf,
-    pub(crate) run: String,
-    pub(crate) expected_status: i32,
+    pub(crate) run: Option<Run>,
+    /// Runs `run` and hooks given as strings, `/bin/sh` by default
+    pub(crate) shell: Option<String>,
+    /// Required with `run`, the default for steps otherwise
+    pub(crate) expected_status: Option<i32>,
+    #[serde(default, rename = "step")]
+    pub(crate) steps: Vec<StepParams>,
+    pub(crate) setup: Option<Hook>,
+    pub(crate) teardown: Option<Hook>,
     pub(crate) ignore: Option<bool>,
+    #[serde(default)]
+    pub(crate) base: OneOrMany<PathBuf>,
+    pub(crate) root_before: Option<RootBeforeParams>,
+    pub(crate) backend: Option<Backend>,
+    pub(crate) overlay: Option<bool>,
+    /// Host files to copy into the root, by their path in the root
+    #[serde(default)]
+    pub(crate) inject: BTreeMap<PathBuf, InjectParams>,
+    /// Host programs to install in the root with their libraries, by name or path
+    #[serde(default)]
+    pub(crate) provision: Vec<String>,
+    #[serde(default)]
+    pub(crate) mock: BTreeMap<String, MockParams>,
+    #[serde(default)]
+    pub(crate) limits: Limits,
+    /// Runs the steps attached to a pseudo-terminal
+    pub(crate) tty: Option<TtyParams>,
+    /// Drives `run`'s stdin instead of input.stdin
+    pub(crate) interaction: Option<Vec<ActionParams>>,
+    pub(crate) fake_time: Option<FakeTimeParams>,
+    /// Runs commands in a known environment instead of one inherited from the host
+    pub(crate) hermetic: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootBeforeParams {
+    pub(crate) from_test: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+#[derive(Debug)]
+pub enum StartingRoot {
+    Root(Root),
+    /// The final root of another test, which has to run first
+    FromTest(String),
+}
+
+#[derive(Debug)]
+pub enum ExpectedRoot {
+    Full(Root),
+    Changes(RootChanges),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            OneOrMany::One(one) => std::slice::from_ref(one).iter(),
+            OneOrMany::Many(many) => many.iter(),
+        }
+    }
+}
+
+/// How tests are run, from the command line
+#[derive(Debug)]
+pub struct RunOptions {
+    pub cleanup: bool,
+    pub include_ignored: bool,
+    pub backend: Backend,
+    pub overlay: bool,
+    /// Files to copy into every test's root, with host paths relative to the working directory
+    pub inject: BTreeMap<PathBuf, InjectParams>,
+    /// Host programs to install in every test's root
+    pub provision: Vec<String>,
+    /// Environment variables of every test, which tests can override
+    pub environment: BTreeMap<String, String>,
+    /// The default for tests that don't set hermetic in Roottest.toml
+    pub hermetic: bool,
+}
+
+/// Parse a `--env` argument, `KEY=VALUE`.
+pub fn parse_env_arg(arg: &str) -> Result<(String, String)> {
+    let (key, value) = arg.split_once('=').context("expected KEY=VALUE")?;
+    Ok((key.to_string(), value.to_string()))
 }
 
 #[derive(Debug)]
 pub struct RootTest {
     pub(crate) name: String,
+    pub(crate) dir: PathBuf,
+    /// The directory given with `--directory` that the test was found in, if it was
+    pub(crate) suite: Option<PathBuf>,
     pub(crate) params: RootTestParams,
-    pub(crate) stdin: Vec<u8>,
-    pub(crate) expected_stdout: Vec<u8>,
-    pub(crate) expected_stderr: Vec<u8>,
+    pub(crate) steps: Vec<Step>,
     pub(crate) environment: BTreeMap<String, String>,
+    pub(crate) hermetic: bool,
+    /// libfaketime, installed in the root for `fake_time`
+    pub(crate) faketime_library: Option<PathBuf>,
 
-    pub(crate) root_before: PathBuf,
+    /// Where the test's root and actual output go, inside the run's work directory
+    pub(crate) work_dir: PathBuf,
+    pub(crate) bases: Vec<Root>,
+    pub(crate) root_before: StartingRoot,
     pub(crate) root: PathBuf,
-    pub(crate) root_after: PathBuf,
-    pub(crate) actual_stdout: PathBuf,
-    pub(crate) actual_stderr: PathBuf,
+    /// Whether tests chained after this one need its root once it's done
+    pub(crate) keep_root: bool,
+    pub(crate) root_after: ExpectedRoot,
 }
 
 impl RootTest {
-    pub fn from_dir(dir: &Path) -> Result<RootTest> {
+    /// Load the test in `dir`, named `test_name`, one case per combination of its `[matrix]`
+    /// axes. Suite defaults are looked for up to `suite`, if the test was found in one.
+    pub fn from_dir(
+        dir: &Path,
+        test_name: &str,
+        suite: Option<&Path>,
+        work_dir: &Path,
+        options: &RunOptions,
+    ) -> Result<Vec<RootTest>> {
         debug!("Loading test from {:?}", dir);
 
-        let params: RootTestParams = toml::from_str(
+        let mut document: toml::Value = toml::from_str(
             &read_to_string(dir.join("Roottest.toml")).context("read roottest.toml")?,
         )
         .context("parse roottest.toml")?;
+        let defaults = Defaults::find(dir, suite).context("load suite defaults")?;
+        trace!("Suite defaults: {:#?}", defaults);
+        defaults.apply(&mut document);
+        let matrix = match document
+            .as_table_mut()
+            .and_then(|table| table.remove("matrix"))
+        {
+            Some(toml::Value::Table(matrix)) => matrix,
+            Some(_) => anyhow::bail!("matrix in roottest.toml is not a table"),
+            None => toml::value::Table::new(),
+        };
+        let cases = Case::expand(&matrix).context("expand matrix")?;
+        trace!("Cases: {:#?}", cases);
+
+        cases
+            .iter()
+            .map(|case| {
+                let name = case.name(test_name);
+                let mut test = RootTest::from_case(
+                    dir, work_dir, options, &defaults, &document, case, test_name,
+                )
+                .with_context(|| format!("load {}", name))?;
+                test.suite = suite.map(Path::to_owned);
+                Ok(test)
+            })
+            .collect()
+    }
+
+    fn from_case(
+        dir: &Path,
+        work_dir: &Path,
+        options: &RunOptions,
+        defaults: &Defaults,
+        document: &toml::Value,
+        case: &Case,
+        test_name: &str,
+    ) -> Result<RootTest> {
+        let mut document = document.clone();
+        case.substitute_commands(&mut document);
+        let params: RootTestParams = document.try_into().context("parse roottest.toml")?;
         trace!("Params: {:#?}", params);
 
-        let stdin = std::fs::read(dir.join("input.stdin")).context("load stdin")?;
-        trace!("Stdin: {:#?}", stdin);
-        let expected_stdout = std::fs::read(dir.join("expected.stdout")).context("load stdout")?;
-        trace!("Stdout: {:#?}", expected_stdout);
-        let expected_stderr = std::fs::read(dir.join("expected.stderr")).context("load stderr")?;
-        trace!("Stderr: {:#?}", expected_stderr);
+        let steps = match (&params.run, params.steps.is_empty()) {
+            (Some(_), false) => anyhow::bail!("run can't be combined with [[step]] tables"),
+            (Some(run), true) => vec![Step::single(
+                dir,
+                case,
+                run,
+                &params.interaction,
+                params
+                    .expected_status
+                    .context("expected_status is required with run")?,
+            )?],
+            (None, false) => {
+                anyhow::ensure!(
+                    params.interaction.is_none(),
+                    "interaction can't be combined with [[step]] tables, give it in a step instead"
+                );
+                Step::load_all(
+                    dir,
+                    case,
+                    &params.steps,
+                    params.expected_status.unwrap_or(0),
+                )?
+            }
+            (None, true) => anyhow::bail!("neither run nor [[step]] tables are given"),
+        };
+        let terminal = params.tty.as_ref().and_then(TtyParams::terminal);
+        anyhow::ensure!(
+            terminal.is_some() || steps.iter().all(|step| step.expected_screen.is_none()),
+            "expected screens can only be compared with tty"
+        );
 
-        let environment = toml::from_str(
-            &read_to_string(dir.join("environment.toml")).context("read environment.toml")?,
-        )
-        .context("parse environment.toml")?;
+        let hermetic = params.hermetic.unwrap_or(options.hermetic);
+        let mut environment = if hermetic {
+            hermetic::environment(&params.cd)
+        } else {
+            BTreeMap::new()
+        };
+        // From the most general to the most specific: the suite, the command line, the test
+        environment.extend(defaults.environment().clone());
+        environment.extend(options.environment.clone());
+        match read_to_string(dir.join("environment.toml")) {
+            Ok(text) => environment.extend(
+                toml::from_str::<BTreeMap<String, String>>(&text)
+                    .context("parse environment.toml")?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("read environment.toml"),
+        }
+        for value in environment.values_mut() {
+            *value = case.substitute(value);
+        }
+        environment.extend(case.values().iter().cloned());
+        mock::prepend_bin_dir(&params.mock, &mut environment);
+        let faketime_library = match &params.fake_time {
+            Some(fake_time) => {
+                let library = faketime::library()?;
+                fake_time.apply(&library, &mut environment)?;
+                Some(library)
+            }
+            None => None,
+        };
         trace!("Environment: {:#?}", environment);
 
+        let bases = params
+            .base
+            .iter()
+            .map(|base| {
+                Root::from_path(&dir.join(base)).with_context(|| format!("load base {:?}", base))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let root_before = match &params.root_before {
+            Some(RootBeforeParams { from_test }) => StartingRoot::FromTest(from_test.clone()),
+            None => StartingRoot::Root(Root::find(dir, "root_before").context("find root_before")?),
+        };
+        trace!("Bases: {:#?}", bases);
+        trace!("Root before: {:#?}", root_before);
+
+        let root_after = match (
+            Root::find_optional(dir, &case.select_root(dir, "root_after")?)?,
+            RootChanges::load(dir, case).context("load changes.toml")?,
+        ) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("root_after can't be combined with changes.toml or root_delta")
+            }
+            (Some(root), None) => ExpectedRoot::Full(root),
+            (None, Some(changes)) => ExpectedRoot::Changes(changes),
+            (None, None) => {
+                anyhow::bail!("neither root_after nor changes.toml or root_delta exists")
+            }
+        };
+        trace!("Expected root: {:#?}", root_after);
+
+        let name = case.name(test_name);
+        let work_dir = work_dir.join(case.dir_name(test_name));
+
         Ok(RootTest {
-            name: dir
-                .file_name()
-                .context("get name of test's directory")?
-                .to_string_lossy()
-                .into(),
+            name,
+            dir: dir.to_owned(),
+            suite: None,
             params,
-            stdin,
-            expected_stdout,
-            expected_stderr,
-            actual_stdout: dir.join("actual.stdout"),
-            actual_stderr: dir.join("actual.stderr"),
+            steps,
             environment,
-            root_before: dir.join("root_before"),
-            root: dir.join("root"),
-            root_after: dir.join("root_after"),
+            hermetic,
+            faketime_library,
+            root: work_dir.join("root"),
+            work_dir,
+            bases,
+            root_before,
+            keep_root: false,
+            root_after,
         })
     }
 
-    pub fn run(&self, cleanup: bool, include_ignored: bool) -> Result<RootTestResult> {
-        if self.params.ignore.unwrap_or(false) && !include_ignored {
+    /// The test whose final root this test starts from, if any.
+    pub fn prerequisite(&self) -> Option<&str> {
+        match &self.root_before {
+            StartingRoot::FromTest(name) => Some(name),
+            StartingRoot::Root(_) => None,
+        }
+    }
+
+    /// Refer to the test this test starts from by `name`.
+    pub fn set_prerequisite(&mut self, name: String) {
+        if let StartingRoot::FromTest(prerequisite) = &mut self.root_before {
+            *prerequisite = name;
+        }
+    }
+
+    /// Stack the layers the test starts from under an empty upper layer. Directories without
+    /// metadata.toml overrides are used in place, other roots are materialized first.
+    fn prepare_overlay(
+        &self,
+        prerequisite: Option<&FinishedRoot>,
+    ) -> Result<(Overlay, MetadataManifest)> {
+        let (lower, virtual_metadata) = match &self.root_before {
+            StartingRoot::Root(root_before) => {
+                let mut lower = Vec::new();
+                let mut unapplied = MetadataManifest::default();
+                let layers = self.bases.iter().chain(std::iter::once(root_before));
+                for (i, layer) in layers.enumerate() {
+                    match &layer.source {
+                        RootSource::Directory(path) if layer.metadata.is_empty() => {
+                            lower.push(path.clone())
+                        }
+                        _ => {
+                            let dest = self.work_dir.join("lower").join(i.to_string());
+                            layer
+                                .materialize(&dest, &mut unapplied)
+                                .with_context(|| format!("copy {:?}", layer.source))?;
+                            lower.push(dest);
+                        }
+                    }
+                }
+                lower.reverse();
+                (lower, unapplied)
+            }
+            StartingRoot::FromTest(_) => {
+                let prerequisite = prerequisite.expect("prerequisite of chained test");
+                (
+                    prerequisite.layers.clone(),
+                    prerequisite.virtual_metadata.clone(),
+                )
+            }
+        };
+
+        let overlay = Overlay {
+            lower,
+            upper: self.work_dir.join("upper"),
+            work: self.work_dir.join("work"),
+        };
+        for dir in &[&self.root, &overlay.upper, &overlay.work] {
+            std::fs::create_dir_all(dir).with_context(|| format!("create {:?}", dir))?;
+        }
+        Ok((overlay, virtual_metadata))
+    }
+
+    /// Bases are only stacked under a chained test's expected root, since the root it starts from
+    /// already contains them.
+    fn load_expected_root(&self, prerequisite: Option<&FinishedRoot>) -> Result<FileNode> {
+        let mut layers: Vec<&Root> = self.bases.iter().collect();
+        match (&self.root_after, &self.root_before) {
+            (ExpectedRoot::Full(root), _) => {
+                layers.push(root);
+                load_layers(&layers)
+            }
+            (ExpectedRoot::Changes(changes), starting_root) => {
+                let mut expected = match starting_root {
+                    StartingRoot::Root(root_before) => {
+                        layers.push(root_before);
+                        load_layers(&layers)?
+                    }
+                    StartingRoot::FromTest(_) => prerequisite
+                        .expect("prerequisite of chained test")
+                        .load()
+                        .context("load prerequisite's root")?,
+                };
+                changes
+                    .apply(&mut expected)
+                    .context("apply changes to root_before")?;
+                Ok(expected)
+            }
+        }
+    }
+
+    /// Run the test, starting from `prerequisite` if it's chained after another test.
+    pub fn run(
+        &self,
+        options: &RunOptions,
+        prerequisite: Option<&FinishedRoot>,
+    ) -> Result<(RootTestResult, Option<FinishedRoot>)> {
+        if self.params.ignore.unwrap_or(false) && !options.include_ignored {
             debug!("Test ignored and include_ignored=false");
-            return Ok(RootTestResult::Ignored);
+            return Ok((RootTestResult::Ignored, None));
         }
 
         if log_enabled!(log::Level::Debug) {
@@ -86,75 +433,509 @@ impl RootTest {
         }
 
         debug!("Cleaning up previous test run");
-        let _ = std::fs::remove_dir_all(&self.root);
-        let _ = std::fs::remove_file(&self.actual_stdout);
-        let _ = std::fs::remove_file(&self.actual_stderr);
-
-        debug!("Copying {:?} to {:?}", self.root_before, self.root);
-
-        let mut root_before = self.root_before.as_os_str().to_owned();
-        root_before.push("/");
-
-        let rsync_success = std::process::Command::new("rsync")
-            .arg("-a")
-            .arg("--super")
-            .arg(&root_before)
-            .arg(&self.root)
-            .stderr(Stdio::null())
-            .output()
-            .context("run rsync -a self.root_before self.root")?
-            .status
-            .success();
-
-        if !rsync_success {
-            if log_enabled!(log::Level::Warn) {
-                println!();
-            }
-            warn!("Root permissions needed to copy files not owned by current user");
-
-            let rsync_success = std::process::Command::new("sudo")
-                .arg("rsync")
-                .arg("-a")
-                .arg(&root_before)
-                .arg(&self.root)
-                .stderr(Stdio::null())
-                .output()
-                .context("run rsync -a self.root_before self.root")?
-                .status
-                .success();
+        let marker = self.work_dir.join(WORK_DIR_MARKER);
+        anyhow::ensure!(
+            self.work_dir.symlink_metadata().is_err()
+                || (self.work_dir.is_dir() && marker.is_file()),
+            "{:?} exists, but isn't the output of a previous run",
+            self.work_dir
+        );
+        remove_existing(&self.work_dir)
+            .with_context(|| format!("remove previous run from {:?}", self.work_dir))?;
+        std::fs::create_dir_all(&self.work_dir)
+            .with_context(|| format!("create work directory {:?}", self.work_dir))?;
+        std::fs::write(&marker, "").context("mark work directory")?;
 
-            anyhow::ensure!(rsync_success, "sudo rsync failed");
-        }
+        let (virtual_metadata, overlay) = if self.params.overlay.unwrap_or(options.overlay) {
+            debug!(
+                "Mounting {:?} copy-on-write on {:?}",
+                self.root_before, self.root
+            );
+            let (overlay, virtual_metadata) = self
+                .prepare_overlay(prerequisite)
+                .context("set up copy-on-write root")?;
+            (virtual_metadata, Some(overlay))
+        } else {
+            debug!("Copying {:?} to {:?}", self.root_before, self.root);
+            let virtual_metadata = match &self.root_before {
+                StartingRoot::Root(root_before) => {
+                    let mut layers: Vec<&Root> = self.bases.iter().collect();
+                    layers.push(root_before);
+                    materialize_layers(&layers, &self.root).context("copy root_before to root")?
+                }
+                StartingRoot::FromTest(name) => prerequisite
+                    .expect("prerequisite of chained test")
+                    .materialize(&self.root)
+                    .with_context(|| format!("copy final root of {} to root", name))?,
+            };
+            (virtual_metadata, None)
+        };
 
-        debug!("Launching chrooted process");
-        let process_output = std::process::Command::new("fakechroot")
-            .arg("chroot")
-            .arg(&self.root)
-            .arg("sh")
-            .arg("-c")
-            .arg(format!("cd {:?} && {}", self.params.cd, self.params.run))
-            .output()
-            .context("run test command in chroot")?;
+        let mut layers = match &overlay {
+            Some(overlay) => vec![overlay.upper.clone()],
+            None => vec![self.root.clone()],
+        };
+        layers.extend(
+            overlay
+                .iter()
+                .flat_map(|overlay| overlay.lower.iter().cloned()),
+        );
+        let mut injection = Injection::new(layers.clone());
+        injection
+            .inject(&self.injected_files(options))
+            .context("inject files into root")?;
+        let mut programs = options.provision.clone();
+        programs.extend(self.params.provision.iter().cloned());
+        // Preloaded from inside the root, except with fakechroot
+        programs.extend(
+            self.faketime_library
+                .iter()
+                .map(|library| library.to_string_lossy().into_owned()),
+        );
+        injection
+            .provision(&programs)
+            .context("provision programs in root")?;
+        mock::install(&self.params.mock, &mut injection).context("install mocks")?;
+        trace!("Injected: {:#?}", injection);
 
-        if !cleanup {
-            debug!("Saving actual stdout and stderr");
-            std::fs::write(&self.actual_stdout, &process_output.stdout)
-                .context("save actual stdout")?;
-            std::fs::write(&self.actual_stderr, &process_output.stderr)
-                .context("save actual stderr")?;
-        }
+        let backend = self.params.backend.unwrap_or(options.backend);
+        let sandbox = backend.sandbox()?;
+        let fuse_mount = match &overlay {
+            Some(overlay) if !sandbox.mounts_overlay() => {
+                Some(FuseMount::mount(overlay, &self.root).context("mount copy-on-write root")?)
+            }
+            _ => None,
+        };
+        let finished_root = FinishedRoot {
+            layers,
+            virtual_metadata,
+            temporary: self.work_dir.clone(),
+        };
+
+        let outcome = match self.run_hook(
+            "setup",
+            &self.params.setup,
+            sandbox.as_ref(),
+            backend,
+            &overlay,
+            options,
+        )? {
+            Some(error) => Err(error),
+            None => {
+                let setup_calls = mock::read_calls(injection.dest())?.len();
+                let step_results = self.run_steps(
+                    sandbox.as_ref(),
+                    backend,
+                    &overlay,
+                    &finished_root,
+                    &injection,
+                    options,
+                )?;
+                let calls = mock::read_calls(injection.dest())?.split_off(setup_calls);
+                match self.run_hook(
+                    "teardown",
+                    &self.params.teardown,
+                    sandbox.as_ref(),
+                    backend,
+                    &overlay,
+                    options,
+                )? {
+                    Some(error) => Err(error),
+                    None => Ok((step_results, calls)),
+                }
+            }
+        };
+        drop(fuse_mount);
+        injection
+            .remove()
+            .context("remove injected files from root")?;
 
         debug!("Generating test results");
-        let result = RootTestResult::new(self, process_output).context("generate test results")?;
+        let result = match outcome {
+            Err(error) => error,
+            Ok((step_results, calls)) => {
+                if !options.cleanup && !self.params.mock.is_empty() {
+                    mock::save_calls(&self.work_dir, &calls)?;
+                }
+                let changed_paths = match &overlay {
+                    Some(overlay) => {
+                        remove_existing(&overlay.work).context("remove overlay work directory")?;
+                        let changed_paths = overlay
+                            .changed_paths()
+                            .context("list paths changed by the program")?;
+                        debug!("Paths changed by the program: {:?}", changed_paths);
+                        Some(changed_paths)
+                    }
+                    None => None,
+                };
+                let steps_not_run = self.steps.len() - step_results.len();
+                let roots = if steps_not_run == 0 {
+                    let mut root = finished_root.load().context("load actual root")?;
+                    let mut root_after = self
+                        .load_expected_root(prerequisite)
+                        .context("load expected root")?;
+                    map_owner(sandbox.as_ref(), &mut root);
+                    map_owner(sandbox.as_ref(), &mut root_after);
+                    Some((root, root_after))
+                } else {
+                    None
+                };
+                let calls = if steps_not_run == 0 {
+                    mock::compare_calls(&self.params.mock, &calls)
+                } else {
+                    None
+                };
+                RootTestResult::new(step_results, steps_not_run, roots, calls, changed_paths)
+                    .context("generate test results")?
+            }
+        };
         trace!("Result: {:#?}", result);
 
-        if cleanup {
+        if self.keep_root && result.ok() {
+            debug!("Keeping root for tests chained after this one");
+            Ok((result, Some(finished_root)))
+        } else if options.cleanup {
             debug!("Cleaning up");
-            std::fs::remove_dir_all(&self.root).context("clean up temporary root directory")?;
+            finished_root
+                .remove()
+                .context("clean up temporary root directory")?;
+            Ok((result, None))
         } else {
             debug!("Not cleaning up");
+            Ok((result, None))
         }
+    }
+
+    /// Run the steps until one fails.
+    fn run_steps(
+        &self,
+        sandbox: &dyn Sandbox,
+        backend: Backend,
+        overlay: &Option<Overlay>,
+        finished_root: &FinishedRoot,
+        injection: &Injection,
+        options: &RunOptions,
+    ) -> Result<Vec<StepResult>> {
+        let mut step_results = Vec::with_capacity(self.steps.len());
+        let terminal = self.params.tty.as_ref().and_then(TtyParams::terminal);
+        for step in &self.steps {
+            let output = self
+                .run_command(
+                    &step.run.argv(self.shell()),
+                    CommandIo {
+                        stdin: &step.stdin,
+                        interaction: step.interaction.as_ref(),
+                        terminal: terminal.as_ref(),
+                        combined: step.expected_output.is_some(),
+                    },
+                    sandbox,
+                    backend,
+                    overlay,
+                )
+                .with_context(|| match &step.name {
+                    Some(name) => format!("run step {}", name),
+                    None => "run test command".to_string(),
+                })?;
+            let mut output = match output {
+                Ok(output) => output,
+                Err(error) => {
+                    step_results.push(StepResult::not_started(step, error));
+                    break;
+                }
+            };
+            if let (Some(terminal), Some(_)) = (&terminal, &step.expected_screen) {
+                output.screen = Some(terminal.screen(&output.stdout));
+            }
+            if terminal.as_ref().is_some_and(Terminal::strip_ansi) {
+                output.stdout = crate::tty::strip_ansi(&output.stdout);
+                output.combined = output
+                    .combined
+                    .map(|combined| crate::tty::strip_ansi(&combined));
+            }
+
+            if !options.cleanup {
+                debug!("Saving actual stdout and stderr");
+                let dir = match &step.name {
+                    Some(name) => self.work_dir.join("steps").join(name),
+                    None => self.work_dir.clone(),
+                };
+                save_output(&dir, "actual", &output.stdout, &output.stderr)?;
+                for (extension, contents) in
+                    &[("output", &output.combined), ("screen", &output.screen)]
+                {
+                    if let Some(contents) = contents {
+                        std::fs::write(dir.join(format!("actual.{}", extension)), contents)
+                            .with_context(|| format!("save actual {}", extension))?;
+                    }
+                }
+            }
+
+            let roots = match &step.root_after {
+                Some(expected) => {
+                    let mut root = finished_root.load().context("load actual root")?;
+                    injection.exclude(&mut root);
+                    let mut layers: Vec<&Root> = self.bases.iter().collect();
+                    layers.push(expected);
+                    let mut root_after = load_layers(&layers).context("load expected root")?;
+                    map_owner(sandbox, &mut root);
+                    map_owner(sandbox, &mut root_after);
+                    Some((root, root_after))
+                }
+                None => None,
+            };
+            let step_result = StepResult::new(step, output, roots);
+            let ok = step_result.ok();
+            step_results.push(step_result);
+            if !ok {
+                debug!("Step failed, not running the steps after it");
+                break;
+            }
+        }
+        Ok(step_results)
+    }
+
+    /// Run the setup or teardown hook, if there is one. Returns the test's result if it failed.
+    fn run_hook(
+        &self,
+        name: &'static str,
+        hook: &Option<Hook>,
+        sandbox: &dyn Sandbox,
+        backend: Backend,
+        overlay: &Option<Overlay>,
+        options: &RunOptions,
+    ) -> Result<Option<RootTestResult>> {
+        let hook = match hook {
+            Some(hook) => hook,
+            None => return Ok(None),
+        };
+
+        debug!("Running {} hook", name);
+        let output = if hook.on_host() {
+            anyhow::ensure!(
+                overlay.is_none() || !sandbox.mounts_overlay(),
+                "{} can't run on the host, the {} backend only mounts the copy-on-write root for the test",
+                name,
+                backend
+            );
+            let root = self.root.canonicalize().context("resolve root directory")?;
+            std::process::Command::new("sh")
+                .arg("-c")
+                .arg(hook.run())
+                .current_dir(&self.dir)
+                .env("ROOTTEST_ROOT", root)
+                .env("ROOTTEST_TEST", &self.name)
+                .stdin(Stdio::null())
+                .output()
+                .context("run sh")
+                .map(Ok)
+        } else {
+            let argv = shell_argv(self.shell(), hook.run());
+            self.run_command(&argv, CommandIo::default(), sandbox, backend, overlay)
+                .map(|output| {
+                    output.map(|output| std::process::Output {
+                        status: output.status,
+                        stdout: output.stdout,
+                        stderr: output.stderr,
+                    })
+                })
+        }
+        .with_context(|| format!("run {}", name))?;
+        let output = match output {
+            Ok(output) => output,
+            Err(failure) => {
+                debug!("{} failed: {}", name, failure);
+                return Ok(Some(RootTestResult::SetupError {
+                    hook: name,
+                    failure,
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }));
+            }
+        };
+
+        if !options.cleanup {
+            save_output(&self.work_dir, name, &output.stdout, &output.stderr)?;
+        }
+
+        if output.status.success() {
+            Ok(None)
+        } else {
+            debug!("{} failed with {}", name, output.status);
+            Ok(Some(RootTestResult::SetupError {
+                hook: name,
+                failure: output.status.to_string(),
+                stdout: output.stdout,
+                stderr: output.stderr,
+            }))
+        }
+    }
 
-        Ok(result)
+    /// The files to copy into the root, with host paths relative to the working directory.
+    /// Files given in Roottest.toml replace files given on the command line.
+    fn injected_files(&self, options: &RunOptions) -> BTreeMap<PathBuf, InjectParams> {
+        let mut files = options.inject.clone();
+        files.extend(self.params.inject.iter().map(|(path, params)| {
+            let params = InjectParams::Table {
+                path: self.dir.join(params.path()),
+                libraries: params.libraries(),
+            };
+            (path.clone(), params)
+        }));
+        files
     }
+
+    fn shell(&self) -> &str {
+        self.params.shell.as_deref().unwrap_or("/bin/sh")
+    }
+
+    /// Run `argv` in the root. Returns why it couldn't be started instead of its output if it
+    /// couldn't.
+    fn run_command(
+        &self,
+        argv: &[String],
+        io: CommandIo,
+        sandbox: &dyn Sandbox,
+        backend: Backend,
+        overlay: &Option<Overlay>,
+    ) -> Result<std::result::Result<CommandOutput, String>> {
+        debug!("Launching chrooted process with {}", backend);
+        let mut command = sandbox.command(
+            &self.root,
+            &Invocation {
+                cd: &self.params.cd,
+                argv,
+                environment: &self.environment,
+                clear_environment: self.hermetic,
+                overlay: overlay.as_ref(),
+            },
+        )?;
+        if self.hermetic {
+            // SAFETY: resetting the process only makes async-signal-safe system calls. It comes
+            // before the limits, which ignore SIGXFSZ.
+            unsafe {
+                command.pre_exec(hermetic::reset_process);
+            }
+        }
+        if !self.params.limits.is_empty() {
+            let limits = self.params.limits.clone();
+            // SAFETY: applying the limits only makes async-signal-safe system calls. It comes
+            // after the sandbox's own setup, which the limits shouldn't apply to.
+            unsafe {
+                command.pre_exec(move || limits.apply());
+            }
+        }
+
+        let pty = match io.terminal {
+            Some(terminal) => {
+                let pty = terminal.open()?;
+                pty.attach(&mut command)?;
+                Some(pty)
+            }
+            None => {
+                command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped());
+                None
+            }
+        };
+        // A program missing from the root or failing to start in it fails the step, not the run
+        let mut process = match command.spawn() {
+            Ok(process) => process,
+            Err(e) => {
+                debug!("Can't start {:?} with {}: {}", argv, backend, e);
+                return Ok(Err(format!("could not start {}: {}", argv[0], e)));
+            }
+        };
+        // The command keeps copies of the terminal, which would keep it open
+        drop(command);
+        let mut streams = match pty {
+            Some(pty) => pty.into_streams()?,
+            None => Streams::from_pipes(&mut process)?,
+        };
+
+        if io.combined {
+            streams.record_combined();
+        }
+        let (transcript, completed) = match io.interaction {
+            Some(interaction) => {
+                let (transcript, completed) = interaction.run(&mut streams)?;
+                (Some(transcript), completed)
+            }
+            None => {
+                streams.send(io.stdin);
+                (None, true)
+            }
+        };
+        let captured = if completed {
+            streams.finish()?
+        } else {
+            debug!("Stopping the program");
+            if let Err(e) = process.kill() {
+                debug!("Can't kill program: {}", e);
+            }
+            streams.abandon()?
+        };
+        let (status, cpu_time) = limits::wait(&process).context("wait for test command")?;
+        Ok(Ok(CommandOutput {
+            status,
+            // Roottest's own SIGKILL isn't a limit
+            limit: if completed {
+                self.params.limits.hit_by(status, cpu_time)
+            } else {
+                None
+            },
+            stdout: captured.stdout,
+            stderr: captured.stderr,
+            combined: captured.combined,
+            transcript,
+            screen: None,
+        }))
+    }
+}
+
+/// How a command's input is given and its output captured. Hooks use the default: no input, and
+/// pipes.
+#[derive(Clone, Copy, Debug, Default)]
+struct CommandIo<'a> {
+    stdin: &'a [u8],
+    interaction: Option<&'a Interaction>,
+    terminal: Option<&'a Terminal>,
+    /// Record both output streams together
+    combined: bool,
+}
+
+/// What a command did.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub status: std::process::ExitStatus,
+    /// The limit that killed it, if one did
+    pub limit: Option<&'static str>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Both streams marked line by line, if they were recorded together
+    pub combined: Option<Vec<u8>>,
+    /// The actions of its interaction that were done, if it has one
+    pub transcript: Option<Vec<u8>>,
+    /// What the terminal showed at the end, if that's checked
+    pub screen: Option<Vec<u8>>,
+}
+
+/// Show files owned by the current user as owned by root if that's how the sandbox shows them to
+/// the program, so roots are compared the way the program sees them.
+fn map_owner(sandbox: &dyn Sandbox, node: &mut FileNode) {
+    if sandbox.maps_current_user_to_root() {
+        // SAFETY: getuid and getgid are always successful
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        node.map_owner_to_root(uid, gid);
+    }
+}
+
+/// Save a command's output in `dir` as `<name>.stdout` and `<name>.stderr`.
+fn save_output(dir: &Path, name: &str, stdout: &[u8], stderr: &[u8]) -> Result<()> {
+    std::fs::create_dir_all(dir).context("create directory for output")?;
+    std::fs::write(dir.join(format!("{}.stdout", name)), stdout)
+        .with_context(|| format!("save {} stdout", name))?;
+    std::fs::write(dir.join(format!("{}.stderr", name)), stderr)
+        .with_context(|| format!("save {} stderr", name))
 }
@@ -0,0 +1,181 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::sandbox::check;
+use crate::streams::Streams;
+
+/// `tty` in `Roottest.toml`, to run the steps attached to a pseudo-terminal instead of pipes:
+///
+/// ```toml
+/// tty = true
+/// tty = { rows = 50, columns = 132, strip_ansi = true }
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TtyParams {
+    Enabled(bool),
+    Table(Terminal),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Terminal {
+    #[serde(default = "default_rows")]
+    rows: u16,
+    #[serde(default = "default_columns")]
+    columns: u16,
+    /// Compare the output without escape sequences, with `\n` line endings
+    #[serde(default)]
+    strip_ansi: bool,
+}
+
+fn default_rows() -> u16 {
+    24
+}
+
+fn default_columns() -> u16 {
+    80
+}
+
+impl TtyParams {
+    pub fn terminal(&self) -> Option<Terminal> {
+        match self {
+            TtyParams::Enabled(false) => None,
+            TtyParams::Enabled(true) => Some(Terminal {
+                rows: default_rows(),
+                columns: default_columns(),
+                strip_ansi: false,
+            }),
+            TtyParams::Table(terminal) => Some(*terminal),
+        }
+    }
+}
+
+impl Terminal {
+    /// Open a pseudo-terminal of this size, with echo turned off so that the output doesn't
+    /// depend on when the program reads its input.
+    pub fn open(&self) -> Result<Pty> {
+        let size = libc::winsize {
+            ws_row: self.rows,
+            ws_col: self.columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: openpty only writes the two descriptors, and the name isn't asked for
+        check(unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        })
+        .context("open pseudo-terminal")?;
+        // SAFETY: openpty returned two new descriptors that nothing else owns
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+        for file in &[&master, &slave] {
+            // SAFETY: fcntl only changes flags of a descriptor we own
+            check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) })
+                .context("set close-on-exec on pseudo-terminal")?;
+        }
+        // SAFETY: termios is plain data, filled in by tcgetattr before it's used
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))
+                .context("get pseudo-terminal attributes")?;
+            termios.c_lflag &= !libc::ECHO;
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))
+                .context("turn off echo")?;
+        }
+        Ok(Pty { master, slave })
+    }
+
+    pub fn strip_ansi(&self) -> bool {
+        self.strip_ansi
+    }
+
+    /// What the terminal shows after `output`, one line per row without trailing spaces or
+    /// empty rows.
+    pub fn screen(&self, output: &[u8]) -> Vec<u8> {
+        let mut parser = vt100::Parser::new(self.rows, self.columns, 0);
+        parser.process(output);
+        let rows: Vec<String> = parser.screen().rows(0, self.columns).collect();
+        let mut screen = String::new();
+        for row in rows {
+            screen.push_str(row.trim_end());
+            screen.push('\n');
+        }
+        while screen.ends_with("\n\n") || screen == "\n" {
+            screen.pop();
+        }
+        screen.into_bytes()
+    }
+}
+
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    /// Make the pseudo-terminal `command`'s stdin, stdout, stderr and controlling terminal.
+    pub fn attach(&self, command: &mut Command) -> Result<()> {
+        let clone = || self.slave.try_clone().context("duplicate pseudo-terminal");
+        command
+            .stdin(Stdio::from(clone()?))
+            .stdout(Stdio::from(clone()?))
+            .stderr(Stdio::from(clone()?));
+        // SAFETY: setsid and ioctl are async-signal-safe
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// The terminal's side of the command's streams, once the command was started.
+    pub fn into_streams(self) -> Result<Streams> {
+        // Only the command's copies are left, so reading fails once they're all closed
+        drop(self.slave);
+        Streams::from_terminal(self.master)
+    }
+}
+
+/// `output` without escape sequences and control characters other than line breaks and tabs,
+/// and with `\n` instead of the terminal's `\r\n`.
+pub fn strip_ansi(output: &[u8]) -> Vec<u8> {
+    struct Text(String);
+
+    impl vte::Perform for Text {
+        fn print(&mut self, c: char) {
+            self.0.push(c);
+        }
+
+        fn execute(&mut self, byte: u8) {
+            if matches!(byte, b'\n' | b'\r' | b'\t') {
+                self.0.push(byte as char);
+            }
+        }
+    }
+
+    let mut parser = vte::Parser::new();
+    let mut text = Text(String::new());
+    for &byte in output {
+        parser.advance(&mut text, byte);
+    }
+    text.0.replace("\r\n", "\n").into_bytes()
+}